@@ -27,5 +27,8 @@ fn main() {
         write_contract(name, &path, &destination);
     }
 
+    tonic_build::compile_protos("proto/tree.proto").unwrap();
+    println!("cargo:rerun-if-changed=proto/tree.proto");
+
     println!("cargo:rerun-if-changed=build.rs");
 }
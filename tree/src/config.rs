@@ -0,0 +1,877 @@
+use crate::failover::RetryConfig;
+use crate::fold::log_query::LogQueryConfig;
+use crate::fold::tree_delegate::TreeKey;
+use crate::fold::verify::VerifyConfig;
+use crate::health::HealthConfig;
+use crate::initial_state::{
+    parse_address, parse_number, parse_u64, InitialStateError,
+};
+use crate::logging::LogFormat;
+
+use ethers::types::U64;
+use serde::Deserialize;
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum ConfigError {
+    #[snafu(display("Could not read config file {}: {}", path.display(), source))]
+    ReadConfigFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not parse config file {}: {}", path.display(), source))]
+    ParseConfigFile {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Invalid value for `{}`: {}", field, err))]
+    InvalidValue { field: String, err: String },
+}
+
+pub type ConfigResult<T> = std::result::Result<T, ConfigError>;
+
+/// Command line flags of `tree_server_main`. Each flag can also be set by an
+/// environment variable or in the TOML config file given by `--config`, in
+/// that order of precedence.
+#[derive(Debug, Default, StructOpt)]
+#[structopt(name = "tree_server_main", about = "Tree delegate gRPC server")]
+pub struct TreeServerOpt {
+    /// Path to a TOML config file
+    #[structopt(long, env = "TREE_SERVER_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Ethereum node JSON-RPC URL, either HTTP or WebSocket
+    #[structopt(long, env = "TREE_SERVER_RPC_URL")]
+    pub rpc_url: Option<String>,
+
+    /// HTTP JSON-RPC URLs to fail over to, in order, when `rpc_url` fails
+    #[structopt(
+        long,
+        env = "TREE_SERVER_FALLBACK_RPC_URLS",
+        use_delimiter = true
+    )]
+    pub fallback_rpc_urls: Option<Vec<String>>,
+
+    /// Retries of a node call failing with a transient error
+    #[structopt(long, env = "TREE_SERVER_RPC_MAX_RETRIES")]
+    pub rpc_max_retries: Option<u32>,
+
+    /// Milliseconds before the first retry, doubling on each retry
+    #[structopt(long, env = "TREE_SERVER_RPC_INITIAL_BACKOFF_MS")]
+    pub rpc_initial_backoff_ms: Option<u64>,
+
+    /// Longest milliseconds between retries
+    #[structopt(long, env = "TREE_SERVER_RPC_MAX_BACKOFF_MS")]
+    pub rpc_max_backoff_ms: Option<u64>,
+
+    /// Seconds a failing endpoint is skipped while others are healthy
+    #[structopt(long, env = "TREE_SERVER_RPC_ENDPOINT_COOLDOWN")]
+    pub rpc_endpoint_cooldown: Option<u64>,
+
+    /// NDJSON file to record every call to the Ethereum node to, over HTTP
+    #[structopt(long, env = "TREE_SERVER_RECORD_RPC", parse(from_os_str))]
+    pub record_rpc: Option<PathBuf>,
+
+    /// Address the gRPC server listens on
+    #[structopt(long, env = "TREE_SERVER_LISTEN_ADDRESS")]
+    pub listen_address: Option<String>,
+
+    /// Address the HTTP/JSON gateway listens on, disabled if unset
+    #[structopt(long, env = "TREE_SERVER_HTTP_LISTEN_ADDRESS")]
+    pub http_listen_address: Option<String>,
+
+    /// Address the Prometheus metrics endpoint listens on, disabled if unset
+    #[structopt(long, env = "TREE_SERVER_METRICS_LISTEN_ADDRESS")]
+    pub metrics_listen_address: Option<String>,
+
+    /// Number of blocks behind the latest to consider final
+    #[structopt(long, env = "TREE_SERVER_SAFETY_MARGIN")]
+    pub safety_margin: Option<usize>,
+
+    /// First block to query for tree events
+    #[structopt(long, env = "TREE_SERVER_GENESIS_BLOCK")]
+    pub genesis_block: Option<u64>,
+
+    /// JSON-RPC error codes providers use for queries over their limits
+    #[structopt(
+        long,
+        env = "TREE_SERVER_QUERY_LIMIT_ERROR_CODES",
+        use_delimiter = true
+    )]
+    pub query_limit_error_codes: Option<Vec<i32>>,
+
+    /// Number of concurrent event queries
+    #[structopt(long, env = "TREE_SERVER_CONCURRENT_EVENTS_FETCH")]
+    pub concurrent_events_fetch: Option<usize>,
+
+    /// Initial block range of `eth_getLogs` queries
+    #[structopt(long, env = "TREE_SERVER_LOG_CHUNK_SIZE")]
+    pub log_chunk_size: Option<u64>,
+
+    /// Smallest block range of `eth_getLogs` queries
+    #[structopt(long, env = "TREE_SERVER_MIN_LOG_CHUNK_SIZE")]
+    pub min_log_chunk_size: Option<u64>,
+
+    /// Largest block range of `eth_getLogs` queries
+    #[structopt(long, env = "TREE_SERVER_MAX_LOG_CHUNK_SIZE")]
+    pub max_log_chunk_size: Option<u64>,
+
+    /// Query the sender of each vertex insertion
+    #[structopt(long, env = "TREE_SERVER_FETCH_SENDERS")]
+    pub fetch_senders: Option<bool>,
+
+    /// Cross-check folded trees against the contract
+    #[structopt(long, env = "TREE_SERVER_VERIFY")]
+    pub verify: Option<bool>,
+
+    /// Number of vertices sampled by each cross-check
+    #[structopt(long, env = "TREE_SERVER_VERIFY_SAMPLE_SIZE")]
+    pub verify_sample_size: Option<usize>,
+
+    /// Seconds to answer a request, unless the client deadline is sooner
+    #[structopt(long, env = "TREE_SERVER_REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+
+    /// Seconds to let open requests finish after a shutdown signal
+    #[structopt(long, env = "TREE_SERVER_SHUTDOWN_GRACE_PERIOD")]
+    pub shutdown_grace_period: Option<u64>,
+
+    /// Trees computed at once by a `GetStates` request
+    #[structopt(long, env = "TREE_SERVER_BATCH_CONCURRENCY")]
+    pub batch_concurrency: Option<usize>,
+
+    /// Trees synced from startup and folded on every new block, each as
+    /// `address[:id[:from_block]]`, `id` being the indexed `_id` of its logs
+    /// and `from_block` the first block queried for them
+    #[structopt(long, env = "TREE_SERVER_TRACK_TREES", use_delimiter = true)]
+    pub track_trees: Option<Vec<String>>,
+
+    /// Seconds between polls for new blocks over HTTP and between retries of
+    /// tracked trees failing to sync
+    #[structopt(long, env = "TREE_SERVER_HEAD_POLL_INTERVAL")]
+    pub head_poll_interval: Option<u64>,
+
+    /// Subscribed trees folded ahead of time before the least recently
+    /// requested ones stop being folded, on top of the tracked trees
+    #[structopt(long, env = "TREE_SERVER_MAX_FOLDED_TREES")]
+    pub max_folded_trees: Option<usize>,
+
+    /// Bytes of serialized responses to cache, zero disabling the cache
+    #[structopt(long, env = "TREE_SERVER_RESPONSE_CACHE_BYTES")]
+    pub response_cache_bytes: Option<usize>,
+
+    /// Chain id the Ethereum node must be on for the server to be ready
+    #[structopt(long, env = "TREE_SERVER_CHAIN_ID")]
+    pub chain_id: Option<u64>,
+
+    /// Blocks a tracked tree may lag behind the chain head while ready
+    #[structopt(long, env = "TREE_SERVER_MAX_TREE_LAG")]
+    pub max_tree_lag: Option<u64>,
+
+    /// Seconds between readiness checks
+    #[structopt(long, env = "TREE_SERVER_HEALTH_CHECK_INTERVAL")]
+    pub health_check_interval: Option<u64>,
+
+    /// Log output format, `text` or `json`
+    #[structopt(long, env = "TREE_SERVER_LOG_FORMAT")]
+    pub log_format: Option<String>,
+
+    /// Log level filter, such as `info` or `info,tree=debug`
+    #[structopt(long, env = "TREE_SERVER_LOG_FILTER")]
+    pub log_filter: Option<String>,
+}
+
+/// Contents of the TOML config file, with the same keys as the flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TreeServerFileConfig {
+    pub rpc_url: Option<String>,
+    pub fallback_rpc_urls: Option<Vec<String>>,
+    pub rpc_max_retries: Option<u32>,
+    pub rpc_initial_backoff_ms: Option<u64>,
+    pub rpc_max_backoff_ms: Option<u64>,
+    pub rpc_endpoint_cooldown: Option<u64>,
+    pub record_rpc: Option<PathBuf>,
+    pub listen_address: Option<String>,
+    pub http_listen_address: Option<String>,
+    pub metrics_listen_address: Option<String>,
+    pub safety_margin: Option<usize>,
+    pub genesis_block: Option<u64>,
+    pub query_limit_error_codes: Option<Vec<i32>>,
+    pub concurrent_events_fetch: Option<usize>,
+    pub log_chunk_size: Option<u64>,
+    pub min_log_chunk_size: Option<u64>,
+    pub max_log_chunk_size: Option<u64>,
+    pub fetch_senders: Option<bool>,
+    pub verify: Option<bool>,
+    pub verify_sample_size: Option<usize>,
+    pub request_timeout: Option<u64>,
+    pub shutdown_grace_period: Option<u64>,
+    pub response_cache_bytes: Option<usize>,
+    pub batch_concurrency: Option<usize>,
+    pub track_trees: Option<Vec<String>>,
+    pub head_poll_interval: Option<u64>,
+    pub max_folded_trees: Option<usize>,
+    pub chain_id: Option<u64>,
+    pub max_tree_lag: Option<u64>,
+    pub health_check_interval: Option<u64>,
+    pub log_format: Option<String>,
+    pub log_filter: Option<String>,
+}
+
+/// Validated `tree_server_main` configuration
+#[derive(Clone, Debug)]
+pub struct TreeServerConfig {
+    pub rpc_url: String,
+    pub fallback_rpc_urls: Vec<String>,
+    pub retry: RetryConfig,
+    pub record_rpc: Option<PathBuf>,
+    pub listen_address: SocketAddr,
+    pub http_listen_address: Option<SocketAddr>,
+    pub metrics_listen_address: Option<SocketAddr>,
+    pub safety_margin: usize,
+    pub genesis_block: U64,
+    pub query_limit_error_codes: Vec<i32>,
+    pub concurrent_events_fetch: usize,
+    pub log_query: LogQueryConfig,
+    pub verify: VerifyConfig,
+    pub fetch_senders: bool,
+    pub request_timeout: Duration,
+    pub shutdown_grace_period: Duration,
+    pub response_cache_bytes: usize,
+    pub batch_concurrency: usize,
+    pub track_trees: Vec<TreeKey>,
+    pub head_poll_interval: Duration,
+    pub max_folded_trees: usize,
+    pub health: HealthConfig,
+    pub log_format: LogFormat,
+    pub log_filter: String,
+}
+
+const DEFAULT_RPC_URL: &str = "http://localhost:8545";
+const DEFAULT_LISTEN_ADDRESS: &str = "[::1]:50051";
+const DEFAULT_CONCURRENT_EVENTS_FETCH: usize = 4;
+const DEFAULT_LOG_FILTER: &str = "info";
+const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 10;
+const DEFAULT_RESPONSE_CACHE_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_BATCH_CONCURRENCY: usize = 8;
+const DEFAULT_HEAD_POLL_INTERVAL: u64 = 4;
+const DEFAULT_MAX_FOLDED_TREES: usize = 1024;
+
+impl TreeServerConfig {
+    /// Build the configuration from the process arguments, environment and
+    /// config file
+    pub fn initialize() -> ConfigResult<Self> {
+        Self::initialize_from(TreeServerOpt::from_args())
+    }
+
+    pub fn initialize_from(opt: TreeServerOpt) -> ConfigResult<Self> {
+        let file = match &opt.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .context(ReadConfigFile { path: path.clone() })?;
+                toml::from_str(&contents)
+                    .context(ParseConfigFile { path: path.clone() })?
+            }
+            None => TreeServerFileConfig::default(),
+        };
+
+        Self::merge(opt, file)
+    }
+
+    /// Merge flags over the config file, then validate the result
+    pub fn merge(
+        opt: TreeServerOpt,
+        file: TreeServerFileConfig,
+    ) -> ConfigResult<Self> {
+        let default_log_query = LogQueryConfig::default();
+        let default_verify = VerifyConfig::default();
+        let default_health = HealthConfig::default();
+
+        let rpc_url = opt
+            .rpc_url
+            .or(file.rpc_url)
+            .unwrap_or_else(|| DEFAULT_RPC_URL.to_string());
+        validate_rpc_url("rpc_url", &rpc_url)?;
+
+        let fallback_rpc_urls = opt
+            .fallback_rpc_urls
+            .or(file.fallback_rpc_urls)
+            .unwrap_or_default();
+        for url in &fallback_rpc_urls {
+            validate_rpc_url("fallback_rpc_urls", url)?;
+            if is_websocket(url) {
+                return InvalidValue {
+                    field: "fallback_rpc_urls",
+                    err: format!("`{}`: only HTTP endpoints fail over", url),
+                }
+                .fail();
+            }
+        }
+        if is_websocket(&rpc_url) && !fallback_rpc_urls.is_empty() {
+            return InvalidValue {
+                field: "fallback_rpc_urls",
+                err: "not supported with a WebSocket `rpc_url`",
+            }
+            .fail();
+        }
+
+        let record_rpc = opt.record_rpc.or(file.record_rpc);
+        if is_websocket(&rpc_url) && record_rpc.is_some() {
+            return InvalidValue {
+                field: "record_rpc",
+                err: "not supported with a WebSocket `rpc_url`",
+            }
+            .fail();
+        }
+
+        let default_retry = RetryConfig::default();
+        let retry = RetryConfig {
+            max_retries: opt
+                .rpc_max_retries
+                .or(file.rpc_max_retries)
+                .unwrap_or(default_retry.max_retries),
+            initial_backoff: opt
+                .rpc_initial_backoff_ms
+                .or(file.rpc_initial_backoff_ms)
+                .map_or(default_retry.initial_backoff, Duration::from_millis),
+            max_backoff: opt
+                .rpc_max_backoff_ms
+                .or(file.rpc_max_backoff_ms)
+                .map_or(default_retry.max_backoff, Duration::from_millis),
+            endpoint_cooldown: opt
+                .rpc_endpoint_cooldown
+                .or(file.rpc_endpoint_cooldown)
+                .map_or(default_retry.endpoint_cooldown, Duration::from_secs),
+        };
+        ensure_positive("rpc_initial_backoff_ms", retry.initial_backoff)?;
+        if retry.initial_backoff > retry.max_backoff {
+            return InvalidValue {
+                field: "rpc_initial_backoff_ms",
+                err: "must not be larger than `rpc_max_backoff_ms`",
+            }
+            .fail();
+        }
+
+        let listen_address = parse_socket_addr(
+            "listen_address",
+            &opt.listen_address
+                .or(file.listen_address)
+                .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string()),
+        )?;
+
+        let http_listen_address = opt
+            .http_listen_address
+            .or(file.http_listen_address)
+            .map(|address| parse_socket_addr("http_listen_address", &address))
+            .transpose()?;
+
+        let metrics_listen_address = opt
+            .metrics_listen_address
+            .or(file.metrics_listen_address)
+            .map(|address| {
+                parse_socket_addr("metrics_listen_address", &address)
+            })
+            .transpose()?;
+
+        let concurrent_events_fetch = opt
+            .concurrent_events_fetch
+            .or(file.concurrent_events_fetch)
+            .unwrap_or(DEFAULT_CONCURRENT_EVENTS_FETCH);
+        ensure_positive("concurrent_events_fetch", concurrent_events_fetch)?;
+
+        let log_query = LogQueryConfig {
+            initial_chunk_size: opt
+                .log_chunk_size
+                .or(file.log_chunk_size)
+                .unwrap_or(default_log_query.initial_chunk_size),
+            min_chunk_size: opt
+                .min_log_chunk_size
+                .or(file.min_log_chunk_size)
+                .unwrap_or(default_log_query.min_chunk_size),
+            max_chunk_size: opt
+                .max_log_chunk_size
+                .or(file.max_log_chunk_size)
+                .unwrap_or(default_log_query.max_chunk_size),
+        };
+        ensure_positive("min_log_chunk_size", log_query.min_chunk_size)?;
+        if log_query.min_chunk_size > log_query.max_chunk_size {
+            return InvalidValue {
+                field: "min_log_chunk_size",
+                err: "must not be larger than `max_log_chunk_size`",
+            }
+            .fail();
+        }
+        if log_query.initial_chunk_size < log_query.min_chunk_size
+            || log_query.initial_chunk_size > log_query.max_chunk_size
+        {
+            return InvalidValue {
+                field: "log_chunk_size",
+                err: "must be between `min_log_chunk_size` and \
+                      `max_log_chunk_size`",
+            }
+            .fail();
+        }
+
+        let verify = VerifyConfig {
+            enabled: opt
+                .verify
+                .or(file.verify)
+                .unwrap_or(default_verify.enabled),
+            sample_size: opt
+                .verify_sample_size
+                .or(file.verify_sample_size)
+                .unwrap_or(default_verify.sample_size),
+        };
+
+        let safety_margin =
+            opt.safety_margin.or(file.safety_margin).unwrap_or(0);
+
+        let health_check_interval = opt
+            .health_check_interval
+            .or(file.health_check_interval)
+            .unwrap_or(default_health.check_interval.as_secs());
+        ensure_positive("health_check_interval", health_check_interval)?;
+
+        let health = HealthConfig {
+            chain_id: opt.chain_id.or(file.chain_id),
+            max_tree_lag: opt
+                .max_tree_lag
+                .or(file.max_tree_lag)
+                .unwrap_or(default_health.max_tree_lag),
+            check_interval: Duration::from_secs(health_check_interval),
+            safety_margin: safety_margin as u64,
+        };
+
+        let request_timeout = opt
+            .request_timeout
+            .or(file.request_timeout)
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        ensure_positive("request_timeout", request_timeout)?;
+
+        let batch_concurrency = opt
+            .batch_concurrency
+            .or(file.batch_concurrency)
+            .unwrap_or(DEFAULT_BATCH_CONCURRENCY);
+        ensure_positive("batch_concurrency", batch_concurrency)?;
+
+        let track_trees = opt
+            .track_trees
+            .or(file.track_trees)
+            .unwrap_or_default()
+            .iter()
+            .map(String::as_str)
+            .map(parse_tree_key)
+            .collect::<ConfigResult<Vec<_>>>()?;
+
+        let head_poll_interval = opt
+            .head_poll_interval
+            .or(file.head_poll_interval)
+            .unwrap_or(DEFAULT_HEAD_POLL_INTERVAL);
+        ensure_positive("head_poll_interval", head_poll_interval)?;
+
+        let max_folded_trees = opt
+            .max_folded_trees
+            .or(file.max_folded_trees)
+            .unwrap_or(DEFAULT_MAX_FOLDED_TREES);
+        ensure_positive("max_folded_trees", max_folded_trees)?;
+
+        let shutdown_grace_period = opt
+            .shutdown_grace_period
+            .or(file.shutdown_grace_period)
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD);
+
+        let log_format = match opt.log_format.or(file.log_format) {
+            Some(format) => {
+                format.parse().map_err(|err| ConfigError::InvalidValue {
+                    field: "log_format".to_string(),
+                    err,
+                })?
+            }
+            None => LogFormat::default(),
+        };
+
+        let log_filter = opt
+            .log_filter
+            .or(file.log_filter)
+            .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string());
+        if let Err(e) = EnvFilter::try_new(&log_filter) {
+            return InvalidValue {
+                field: "log_filter",
+                err: format!("`{}`: {}", log_filter, e),
+            }
+            .fail();
+        }
+
+        Ok(TreeServerConfig {
+            rpc_url,
+            fallback_rpc_urls,
+            retry,
+            record_rpc,
+            listen_address,
+            http_listen_address,
+            metrics_listen_address,
+            safety_margin,
+            genesis_block: U64::from(
+                opt.genesis_block.or(file.genesis_block).unwrap_or(0),
+            ),
+            query_limit_error_codes: opt
+                .query_limit_error_codes
+                .or(file.query_limit_error_codes)
+                .unwrap_or_default(),
+            concurrent_events_fetch,
+            log_query,
+            verify,
+            fetch_senders: opt
+                .fetch_senders
+                .or(file.fetch_senders)
+                .unwrap_or_default(),
+            request_timeout: Duration::from_secs(request_timeout),
+            shutdown_grace_period: Duration::from_secs(shutdown_grace_period),
+            response_cache_bytes: opt
+                .response_cache_bytes
+                .or(file.response_cache_bytes)
+                .unwrap_or(DEFAULT_RESPONSE_CACHE_BYTES),
+            batch_concurrency,
+            track_trees,
+            head_poll_interval: Duration::from_secs(head_poll_interval),
+            max_folded_trees,
+            health,
+            log_format,
+            log_filter,
+        })
+    }
+
+    /// Whether the node is reached over WebSocket, allowing new heads to be
+    /// followed by subscription
+    pub fn is_websocket(&self) -> bool {
+        is_websocket(&self.rpc_url)
+    }
+
+    /// `rpc_url` followed by the fallback endpoints
+    pub fn rpc_urls(&self) -> Vec<String> {
+        std::iter::once(&self.rpc_url)
+            .chain(&self.fallback_rpc_urls)
+            .cloned()
+            .collect()
+    }
+}
+
+fn is_websocket(rpc_url: &str) -> bool {
+    rpc_url.starts_with("ws://") || rpc_url.starts_with("wss://")
+}
+
+fn validate_rpc_url(field: &str, rpc_url: &str) -> ConfigResult<()> {
+    let url =
+        url::Url::parse(rpc_url).map_err(|e| ConfigError::InvalidValue {
+            field: field.to_string(),
+            err: format!("`{}`: {}", rpc_url, e),
+        })?;
+
+    match url.scheme() {
+        "http" | "https" | "ws" | "wss" => Ok(()),
+        scheme => InvalidValue {
+            field,
+            err: format!("unsupported scheme `{}`", scheme),
+        }
+        .fail(),
+    }
+}
+
+fn parse_socket_addr(field: &str, address: &str) -> ConfigResult<SocketAddr> {
+    address.parse().map_err(|e| ConfigError::InvalidValue {
+        field: field.to_string(),
+        err: format!("`{}`: {}", address, e),
+    })
+}
+
+fn ensure_positive<T: Default + PartialEq>(
+    field: &str,
+    value: T,
+) -> ConfigResult<()> {
+    if value == T::default() {
+        InvalidValue {
+            field,
+            err: "must be greater than zero",
+        }
+        .fail()
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse a tracked tree as `address[:id[:from_block]]`
+fn parse_tree_key(tree: &str) -> ConfigResult<TreeKey> {
+    let mut parts = tree.split(':');
+    let tree_address = parse_address(
+        "track_trees",
+        &Value::String(parts.next().unwrap().into()),
+    )
+    .map_err(invalid_tree)?;
+
+    let mut key = TreeKey::new(tree_address);
+    if let Some(id) = parts.next() {
+        key.identifier = parse_number("track_trees", &Value::String(id.into()))
+            .map_err(invalid_tree)?;
+    }
+    if let Some(from_block) = parts.next() {
+        key.from_block = U64::from(
+            parse_u64("track_trees", &Value::String(from_block.into()))
+                .map_err(invalid_tree)?,
+        );
+    }
+
+    if parts.next().is_some() {
+        return InvalidValue {
+            field: "track_trees",
+            err: format!("`{}` is not `address[:id[:from_block]]`", tree),
+        }
+        .fail();
+    }
+
+    Ok(key)
+}
+
+fn invalid_tree(e: InitialStateError) -> ConfigError {
+    match e {
+        InitialStateError::InvalidField { field, err } => {
+            ConfigError::InvalidValue { field, err }
+        }
+        e => ConfigError::InvalidValue {
+            field: "track_trees".to_string(),
+            err: e.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ethers::types::Address;
+
+    #[test]
+    fn test_defaults() {
+        let config = TreeServerConfig::merge(
+            TreeServerOpt::default(),
+            TreeServerFileConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(config.rpc_url, DEFAULT_RPC_URL);
+        assert_eq!(config.rpc_urls(), vec![DEFAULT_RPC_URL.to_string()]);
+        assert_eq!(config.retry, RetryConfig::default());
+        assert_eq!(config.listen_address, "[::1]:50051".parse().unwrap());
+        assert_eq!(config.safety_margin, 0);
+        assert_eq!(config.genesis_block, U64::zero());
+        assert_eq!(config.concurrent_events_fetch, 4);
+        assert_eq!(config.http_listen_address, None);
+        assert_eq!(config.metrics_listen_address, None);
+        assert_eq!(config.log_query, LogQueryConfig::default());
+        assert!(!config.fetch_senders);
+        assert_eq!(config.health, HealthConfig::default());
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.log_filter, "info");
+        assert_eq!(config.response_cache_bytes, 64 * 1024 * 1024);
+        assert_eq!(config.batch_concurrency, 8);
+        assert!(config.track_trees.is_empty());
+        assert_eq!(config.record_rpc, None);
+        assert_eq!(config.head_poll_interval, Duration::from_secs(4));
+        assert_eq!(config.max_folded_trees, 1024);
+        assert_eq!(config.request_timeout, Duration::from_secs(60));
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(10));
+        assert!(!config.is_websocket());
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let file: TreeServerFileConfig = toml::from_str(
+            r#"
+            rpc_url = "https://node.example:8545"
+            fallback_rpc_urls = ["https://backup.example"]
+            rpc_max_retries = 2
+            listen_address = "0.0.0.0:50051"
+            http_listen_address = "0.0.0.0:8080"
+            safety_margin = 10
+            genesis_block = 100
+            "#,
+        )
+        .unwrap();
+        let opt = TreeServerOpt {
+            safety_margin: Some(20),
+            rpc_max_retries: Some(7),
+            ..Default::default()
+        };
+
+        let config = TreeServerConfig::merge(opt, file).unwrap();
+        assert_eq!(config.rpc_url, "https://node.example:8545");
+        assert_eq!(
+            config.rpc_urls(),
+            vec![
+                "https://node.example:8545".to_string(),
+                "https://backup.example".to_string()
+            ]
+        );
+        assert_eq!(config.retry.max_retries, 7);
+        assert_eq!(config.listen_address, "0.0.0.0:50051".parse().unwrap());
+        assert_eq!(
+            config.http_listen_address,
+            Some("0.0.0.0:8080".parse().unwrap())
+        );
+        assert_eq!(config.safety_margin, 20);
+        assert_eq!(config.health.safety_margin, 20);
+        assert_eq!(config.genesis_block, U64::from(100));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let invalid = |opt: TreeServerOpt| {
+            TreeServerConfig::merge(opt, TreeServerFileConfig::default())
+                .is_err()
+        };
+
+        assert!(invalid(TreeServerOpt {
+            rpc_url: Some("localhost".to_string()),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            rpc_url: Some("ipc:///tmp/geth.ipc".to_string()),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            fallback_rpc_urls: Some(vec!["wss://backup.example".to_string()]),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            rpc_url: Some("ws://localhost:8546".to_string()),
+            fallback_rpc_urls: Some(vec!["http://backup.example".to_string()]),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            rpc_url: Some("ws://localhost:8546".to_string()),
+            record_rpc: Some(PathBuf::from("calls.ndjson")),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            rpc_initial_backoff_ms: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            rpc_initial_backoff_ms: Some(500),
+            rpc_max_backoff_ms: Some(100),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            listen_address: Some("localhost".to_string()),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            http_listen_address: Some("8080".to_string()),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            concurrent_events_fetch: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            batch_concurrency: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            track_trees: Some(vec!["0x1234".to_string()]),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            head_poll_interval: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            max_folded_trees: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            request_timeout: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            log_format: Some("yaml".to_string()),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            health_check_interval: Some(0),
+            ..Default::default()
+        }));
+        assert!(invalid(TreeServerOpt {
+            min_log_chunk_size: Some(10),
+            max_log_chunk_size: Some(5),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_track_trees() {
+        let address = "0x0000000000000000000000000000000000000001";
+        let opt = TreeServerOpt {
+            track_trees: Some(vec![
+                address.to_string(),
+                format!("{}:3", address),
+                format!("{}:0x3:15000000", address),
+            ]),
+            ..Default::default()
+        };
+
+        let config =
+            TreeServerConfig::merge(opt, TreeServerFileConfig::default())
+                .unwrap();
+        let tree = TreeKey::new(Address::from_low_u64_be(1));
+        assert_eq!(
+            config.track_trees,
+            vec![
+                tree,
+                TreeKey {
+                    identifier: 3.into(),
+                    ..tree
+                },
+                TreeKey {
+                    identifier: 3.into(),
+                    from_block: U64::from(15_000_000),
+                    ..tree
+                },
+            ]
+        );
+
+        let invalid_trees = vec![
+            ":1".to_string(),
+            format!("{}:x", address),
+            format!("{}:1:-1", address),
+            format!("{}:1:2:3", address),
+        ];
+        for invalid in invalid_trees {
+            let opt = TreeServerOpt {
+                track_trees: Some(vec![invalid.clone()]),
+                ..Default::default()
+            };
+            assert!(
+                TreeServerConfig::merge(opt, TreeServerFileConfig::default())
+                    .is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_file_key() {
+        let file: std::result::Result<TreeServerFileConfig, _> =
+            toml::from_str("rpc_uri = \"http://localhost:8545\"");
+        assert!(file.is_err(), "Unknown keys should be rejected");
+    }
+}
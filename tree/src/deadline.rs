@@ -0,0 +1,112 @@
+use std::future::Future;
+use std::time::Duration;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Status};
+
+/// Time left to answer `request`: the sooner of the deadline the client sent
+/// in its `grpc-timeout` header and `limit`
+pub fn timeout_of<T>(
+    request: &Request<T>,
+    limit: Option<Duration>,
+) -> Option<Duration> {
+    match (grpc_timeout(request.metadata()), limit) {
+        (Some(client), Some(limit)) => Some(client.min(limit)),
+        (client, limit) => client.or(limit),
+    }
+}
+
+/// Answer `request` within `timeout`, failing with `DeadlineExceeded` and
+/// cancelling it once it elapses
+pub async fn with_deadline<T, F>(
+    timeout: Option<Duration>,
+    request: F,
+) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return request.await,
+    };
+
+    tokio::time::timeout(timeout, request)
+        .await
+        .unwrap_or_else(|_| {
+            Err(Status::new(
+                Code::DeadlineExceeded,
+                format!("Request not answered within {:?}", timeout),
+            ))
+        })
+}
+
+fn grpc_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    parse_grpc_timeout(metadata.get("grpc-timeout")?.to_str().ok()?)
+}
+
+/// Parse a `grpc-timeout` header: at most 8 digits followed by a unit
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if !value.is_ascii() || value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(
+            parse_grpc_timeout("150m"),
+            Some(Duration::from_millis(150))
+        );
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None, "Too many digits");
+        assert_eq!(parse_grpc_timeout("10x"), None);
+    }
+
+    #[test]
+    fn test_timeout_of() {
+        let mut request = Request::new(());
+        let limit = Some(Duration::from_secs(30));
+        assert_eq!(timeout_of(&request, limit), limit);
+        assert_eq!(timeout_of(&request, None), None);
+
+        request
+            .metadata_mut()
+            .insert("grpc-timeout", "500m".parse().unwrap());
+        assert_eq!(
+            timeout_of(&request, limit),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        };
+
+        let result = with_deadline(Some(Duration::from_millis(10)), slow).await;
+        assert_eq!(result.unwrap_err().code(), Code::DeadlineExceeded);
+
+        let fast = async { Ok(1) };
+        assert_eq!(with_deadline(None, fast).await.unwrap(), 1);
+    }
+}
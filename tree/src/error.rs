@@ -1,17 +1,213 @@
+use ethers::types::{H256, U256, U64};
 use snafu::Snafu;
+use tonic::{Code, Status};
 
+/// Errors of the tree and of its delegate. Requests failing with an error are
+/// answered with the gRPC code of `Error::code`:
+///
+/// | Variant                    | Code              |
+/// | -------------------------- | ----------------- |
+/// | `VertexNotFound`           | `NotFound`        |
+/// | `BlockNotFound`            | `NotFound`        |
+/// | `TreeEmpty`                | `NotFound`        |
+/// | `AncestorDeeperThanVertex` | `InvalidArgument` |
+/// | `ParentNotFound`           | `DataLoss`        |
+/// | `AncestorNotFound`         | `DataLoss`        |
+/// | `DepthMismatch`            | `DataLoss`        |
+/// | `DeepestMismatch`          | `DataLoss`        |
+/// | `ParentAmbiguous`          | `DataLoss`        |
+/// | `LogMalformed`             | `DataLoss`        |
+/// | `TreeDivergent`            | `DataLoss`        |
+/// | `TreeUnavailable`          | `Unavailable`     |
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub")]
 pub enum Error {
-    #[snafu(display("Vertex not found in tree: {}", err))]
-    VertexNotFound { err: String },
-    #[snafu(display("Tree in malformed state: {}", err))]
-    TreeMalformed { err: String },
+    #[snafu(display(
+        "Vertex {} not found in tree of size {}",
+        index,
+        tree_size
+    ))]
+    VertexNotFound { index: u32, tree_size: usize },
+    #[snafu(display("Block {} not found", block))]
+    BlockNotFound { block: String },
+    #[snafu(display("Tree is empty"))]
+    TreeEmpty {},
+    #[snafu(display(
+        "Vertex {} at depth {} has no ancestor at depth {}",
+        index,
+        vertex_depth,
+        depth
+    ))]
+    AncestorDeeperThanVertex {
+        index: u32,
+        vertex_depth: u32,
+        depth: u32,
+    },
+    #[snafu(display(
+        "Tree in malformed state: parent {} not found in tree of size {}",
+        parent,
+        tree_size
+    ))]
+    ParentNotFound { parent: u32, tree_size: usize },
+    #[snafu(display(
+        "Tree in malformed state: ancestor of {} at depth {} not found",
+        index,
+        depth
+    ))]
+    AncestorNotFound { index: u32, depth: u32 },
+    #[snafu(display(
+        "Tree in malformed state: vertex {} stored at depth {}, rebuilt at \
+         depth {}",
+        index,
+        stored_depth,
+        depth
+    ))]
+    DepthMismatch {
+        index: u32,
+        stored_depth: u32,
+        depth: u32,
+    },
+    #[snafu(display(
+        "Tree in malformed state: deepest vertex stored as {} at depth {}, \
+         rebuilt as {} at depth {}",
+        stored_index,
+        stored_depth,
+        index,
+        depth
+    ))]
+    DeepestMismatch {
+        stored_index: u32,
+        stored_depth: u32,
+        index: u32,
+        depth: u32,
+    },
+    #[snafu(display(
+        "Parent of vertex {} is one of {} vertices at depth {}, which storage \
+         can't tell apart",
+        index,
+        candidates,
+        depth
+    ))]
+    ParentAmbiguous {
+        index: u32,
+        depth: u32,
+        candidates: usize,
+    },
+    #[snafu(display(
+        "Malformed vertex inserted log {} of block {}: {}",
+        log_index,
+        block_number,
+        err
+    ))]
+    LogMalformed {
+        block_number: U64,
+        log_index: U256,
+        err: String,
+    },
+    #[snafu(display(
+        "Tree diverges from on-chain state at block {:?}: {}",
+        block_hash,
+        err
+    ))]
+    TreeDivergent { block_hash: H256, err: String },
     #[snafu(display("Middleware error `{}`: {} ", source, err))]
     TreeUnavailable {
-        source: Box<dyn std::error::Error>,
+        source: Box<dyn std::error::Error + Send + Sync>,
         err: String,
     },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// gRPC code of requests failing with this error
+    pub fn code(&self) -> Code {
+        match self {
+            Error::VertexNotFound { .. }
+            | Error::BlockNotFound { .. }
+            | Error::TreeEmpty { .. } => Code::NotFound,
+            Error::AncestorDeeperThanVertex { .. } => Code::InvalidArgument,
+            Error::ParentNotFound { .. }
+            | Error::AncestorNotFound { .. }
+            | Error::DepthMismatch { .. }
+            | Error::DeepestMismatch { .. }
+            | Error::ParentAmbiguous { .. }
+            | Error::LogMalformed { .. }
+            | Error::TreeDivergent { .. } => Code::DataLoss,
+            Error::TreeUnavailable { .. } => Code::Unavailable,
+        }
+    }
+}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        Status::new(error.code(), format!("{}", error))
+    }
+}
+
+/// gRPC code of a failure wrapping an `Error`, such as a fold error, or
+/// `Unavailable` when no `Error` is among its sources
+pub fn code_of(error: &(dyn std::error::Error + 'static)) -> Code {
+    let mut source = Some(error);
+
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<Error>() {
+            return error.code();
+        }
+        source = error.source();
+    }
+
+    Code::Unavailable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(display("Fold failed: {}", source))]
+    struct FoldFailed {
+        source: Error,
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Error>();
+    }
+
+    #[test]
+    fn test_code_of() {
+        let not_found = Error::VertexNotFound {
+            index: 3,
+            tree_size: 2,
+        };
+        assert_eq!(code_of(&not_found), Code::NotFound);
+        assert_eq!(code_of(&Error::TreeEmpty {}), Code::NotFound);
+
+        let wrapped = FoldFailed {
+            source: Error::ParentNotFound {
+                parent: 5,
+                tree_size: 1,
+            },
+        };
+        assert_eq!(code_of(&wrapped), Code::DataLoss);
+
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "timed out");
+        assert_eq!(code_of(&io), Code::Unavailable);
+    }
+
+    #[test]
+    fn test_status() {
+        let status = Status::from(Error::AncestorDeeperThanVertex {
+            index: 1,
+            vertex_depth: 1,
+            depth: 4,
+        });
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "Vertex 1 at depth 1 has no ancestor at depth 4"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use crate::metrics;
+
+use ethers::providers::Middleware;
+use ethers::types::{Filter, Log, U64};
+
+/// Block range limits for `eth_getLogs` queries. Hosted providers reject
+/// queries spanning too many blocks or returning too many results, so logs are
+/// fetched in chunks of `initial_chunk_size` blocks, halving the chunk on a
+/// limit error (down to `min_chunk_size`) and doubling it on success (up to
+/// `max_chunk_size`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogQueryConfig {
+    pub initial_chunk_size: u64,
+    pub min_chunk_size: u64,
+    pub max_chunk_size: u64,
+}
+
+impl Default for LogQueryConfig {
+    fn default() -> Self {
+        LogQueryConfig {
+            initial_chunk_size: 10_000,
+            min_chunk_size: 1,
+            max_chunk_size: 100_000,
+        }
+    }
+}
+
+/// Error messages providers use when a query exceeds their range or result
+/// limits. They are specific enough not to match other errors about block
+/// ranges, such as an invalid one.
+const LIMIT_ERROR_PATTERNS: [&str; 9] = [
+    "query returned more than",
+    "block range is too wide",
+    "range is too large",
+    "range too large",
+    "limit exceeded",
+    "too many results",
+    "response size exceeded",
+    "response size should not",
+    "-32005",
+];
+
+/// Whether `err` is a provider complaining about the size of a log query
+pub fn is_limit_error<E: std::error::Error>(err: &E) -> bool {
+    let message = err.to_string().to_lowercase();
+    LIMIT_ERROR_PATTERNS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+/// Query all logs matching `filter` in `[from_block, to_block]`, in adaptive
+/// block range chunks
+pub async fn query_logs<M: Middleware>(
+    client: &M,
+    filter: &Filter,
+    from_block: U64,
+    to_block: U64,
+    config: &LogQueryConfig,
+) -> std::result::Result<Vec<Log>, M::Error> {
+    let min_chunk = config.min_chunk_size.max(1);
+    let max_chunk = config.max_chunk_size.max(min_chunk);
+    let mut chunk = config.initial_chunk_size.max(min_chunk).min(max_chunk);
+
+    let to_block = to_block.as_u64();
+    let mut start = from_block.as_u64();
+    let mut logs = Vec::new();
+
+    while start <= to_block {
+        let end = start.saturating_add(chunk - 1).min(to_block);
+        let chunk_filter = filter
+            .clone()
+            .from_block(U64::from(start))
+            .to_block(U64::from(end));
+
+        metrics::inc_rpc_calls("eth_getLogs");
+        match client.get_logs(&chunk_filter).await {
+            Ok(mut chunk_logs) => {
+                logs.append(&mut chunk_logs);
+                chunk = chunk.saturating_mul(2).min(max_chunk);
+
+                if end == to_block {
+                    break;
+                }
+                start = end + 1;
+            }
+            Err(e) if chunk > min_chunk && is_limit_error(&e) => {
+                chunk = (chunk / 2).max(min_chunk);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_chain::{vertex_inserted, MockChain, MockChainError};
+
+    use ethers::types::{Address, U256};
+
+    /// Chain with one log in each of its first `blocks` blocks after genesis,
+    /// rejecting queries over `max_range` blocks
+    fn chain(blocks: u64, max_range: u64) -> MockChain {
+        let chain = MockChain::new();
+        for _ in 0..blocks {
+            chain.mine(vec![vertex_inserted(Address::zero(), U256::zero(), 0)]);
+        }
+        chain.limit_log_range(max_range);
+        chain
+    }
+
+    fn config(initial: u64, min: u64, max: u64) -> LogQueryConfig {
+        LogQueryConfig {
+            initial_chunk_size: initial,
+            min_chunk_size: min,
+            max_chunk_size: max,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_logs_shrinks_on_limit_error() {
+        let client = chain(100, 10);
+        let logs = query_logs(
+            &client,
+            &Filter::new(),
+            U64::from(1),
+            U64::from(100),
+            &config(64, 1, 64),
+        )
+        .await
+        .expect("Query should succeed with smaller chunks");
+
+        let blocks: Vec<u64> = logs
+            .iter()
+            .map(|log| log.block_number.unwrap().as_u64())
+            .collect();
+        assert_eq!(blocks, (1..=100).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn test_query_logs_grows_on_success() {
+        let client = chain(1_023, 1_000);
+        let logs = query_logs(
+            &client,
+            &Filter::new(),
+            U64::from(1),
+            U64::from(1_023),
+            &config(1, 1, 512),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(logs.len(), 1_023);
+        // 1 + 2 + 4 + ... + 512 = 1023 blocks in 10 queries
+        assert_eq!(client.log_queries(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_query_logs_fails_at_min_chunk() {
+        let client = chain(100, 10);
+        let result = query_logs(
+            &client,
+            &Filter::new(),
+            U64::from(1),
+            U64::from(100),
+            &config(64, 32, 64),
+        )
+        .await;
+
+        assert!(result.is_err(), "Query should fail below min chunk size");
+    }
+
+    #[test]
+    fn test_is_limit_error() {
+        assert!(is_limit_error(&MockChainError(
+            "query returned more than 10000 results".into()
+        )));
+        assert!(is_limit_error(&MockChainError(
+            "eth_getLogs block range is too large".into()
+        )));
+        assert!(is_limit_error(&MockChainError(
+            "block range is too wide".into()
+        )));
+        assert!(!is_limit_error(&MockChainError("connection reset".into())));
+        assert!(!is_limit_error(&MockChainError(
+            "invalid block range".into()
+        )));
+    }
+}
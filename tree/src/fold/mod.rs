@@ -1,2 +1,6 @@
 pub mod contracts;
+pub mod log_query;
+pub mod reorg;
+pub mod storage_loader;
 pub mod tree_delegate;
+pub mod verify;
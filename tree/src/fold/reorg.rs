@@ -0,0 +1,252 @@
+use crate::error::*;
+
+use super::tree_delegate::{compute_state, TreeState};
+
+use offchain_core::types::Block;
+
+use ethers::providers::Middleware;
+use ethers::types::{BlockId, BlockNumber, H256, U64};
+use snafu::ResultExt;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Brings `state` up to `block`, which may be on a different chain than the
+/// one `state` was computed on. Vertices from orphaned blocks are rolled back
+/// by truncating the tree, and only logs after the common ancestor are queried,
+/// instead of recomputing the tree from `from_block`, the first block of the
+/// tree, which is only done when no block of `state` is left on chain.
+pub async fn advance<M: Middleware + 'static>(
+    access: Arc<M>,
+    state: &TreeState,
+    block: &Block,
+    from_block: U64,
+) -> Result<TreeState> {
+    let (rewound, from_block) =
+        match find_common_ancestor(access.as_ref(), state).await? {
+            Some((number, _)) if number >= block.number => {
+                // `block` is already known to `state`
+                return Ok(state.rewind(block.number, block.hash));
+            }
+            Some((number, hash)) => {
+                if number < state.block_number {
+                    info!(
+                        tree = ?state.caller_address,
+                        ancestor = %number,
+                        "Rewinding tree orphaned by a reorg"
+                    );
+                }
+                (state.rewind(number, hash), number + 1)
+            }
+            None => {
+                warn!(
+                    tree = ?state.caller_address,
+                    from_block = %from_block,
+                    "No block of tree left on chain, recomputing it"
+                );
+                (
+                    TreeState {
+                        tree: None,
+                        provenance: Default::default(),
+                        ..state.rewind(U64::zero(), H256::zero())
+                    },
+                    from_block,
+                )
+            }
+        };
+
+    compute_state(
+        access,
+        rewound.caller_address,
+        rewound.identifier,
+        Some(&rewound),
+        block,
+        Some(from_block),
+        &state.config,
+    )
+    .await
+}
+
+/// Finds the latest block known to `state` that is still on the canonical
+/// chain: either the block `state` was computed at, or the latest block that
+/// inserted a vertex. Returns `None` when no such block is left.
+async fn find_common_ancestor<M: Middleware + 'static>(
+    access: &M,
+    state: &TreeState,
+) -> Result<Option<(U64, H256)>> {
+    if is_canonical(access, state.block_number, state.block_hash).await? {
+        return Ok(Some((state.block_number, state.block_hash)));
+    }
+
+    let size = state.tree.as_ref().map(|tree| tree.size()).unwrap_or(0);
+    let mut checked: Option<U64> = None;
+
+    for index in (0..size as u32).rev() {
+        let provenance = match state.get_provenance(index) {
+            Some(provenance) => provenance,
+            None => continue,
+        };
+
+        // vertices of the same block share the answer
+        if checked == Some(provenance.block_number) {
+            continue;
+        }
+        checked = Some(provenance.block_number);
+
+        if is_canonical(access, provenance.block_number, provenance.block_hash)
+            .await?
+        {
+            return Ok(Some((provenance.block_number, provenance.block_hash)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Block of a new head, once it has a hash and a number
+pub fn block_of(head: &ethers::types::Block<H256>) -> Option<Block> {
+    Some(Block {
+        hash: head.hash?,
+        number: head.number?,
+        parent_hash: head.parent_hash,
+        timestamp: head.timestamp,
+        logs_bloom: head.logs_bloom.unwrap_or_default(),
+    })
+}
+
+async fn is_canonical<M: Middleware + 'static>(
+    access: &M,
+    number: U64,
+    hash: H256,
+) -> Result<bool> {
+    let block = access
+        .get_block(BlockId::Number(BlockNumber::Number(number)))
+        .await
+        .map_err(|e| e.into())
+        .context(TreeUnavailable {
+            err: format!("Error querying for block {}", number),
+        })?;
+
+    Ok(block.and_then(|block| block.hash) == Some(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_chain::{vertex_inserted, MockChain};
+
+    use ethers::types::{Address, Log, U256};
+
+    fn tree_address() -> Address {
+        Address::from_low_u64_be(1)
+    }
+
+    fn vertices(parents: &[u32]) -> Vec<Log> {
+        parents
+            .iter()
+            .map(|parent| {
+                vertex_inserted(tree_address(), U256::zero(), *parent)
+            })
+            .collect()
+    }
+
+    fn head(chain: &MockChain) -> Block {
+        block_of(&chain.head()).unwrap()
+    }
+
+    async fn sync(chain: &Arc<MockChain>, from_block: u64) -> TreeState {
+        let empty = TreeState {
+            caller_address: tree_address(),
+            identifier: U256::zero(),
+            tree: None,
+            provenance: Default::default(),
+            block_number: U64::zero(),
+            block_hash: H256::zero(),
+            config: Default::default(),
+        };
+        advance(Arc::clone(chain), &empty, &head(chain), from_block.into())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_advance_without_reorg() {
+        let chain = Arc::new(MockChain::new());
+        chain.mine(vertices(&[0]));
+        chain.mine(vertices(&[0, 1]));
+        let state = sync(&chain, 0).await;
+        assert_eq!(state.tree.as_ref().unwrap().size(), 3);
+
+        chain.mine(vec![]);
+        let block = chain.mine(vertices(&[2]));
+        let state =
+            advance(Arc::clone(&chain), &state, &head(&chain), U64::zero())
+                .await
+                .unwrap();
+
+        let tree = state.tree.as_ref().unwrap();
+        assert_eq!(tree.size(), 4);
+        assert_eq!(tree.get_deepest(), Some(3));
+        assert_eq!(state.block_number, U64::from(4));
+        assert_eq!(Some(state.block_hash), block.hash);
+    }
+
+    #[tokio::test]
+    async fn test_advance_rewinds_orphaned_vertices() {
+        let chain = Arc::new(MockChain::new());
+        chain.mine(vertices(&[0]));
+        chain.mine(vertices(&[0, 1]));
+        chain.mine(vertices(&[2, 2]));
+        let state = sync(&chain, 0).await;
+        assert_eq!(state.tree.as_ref().unwrap().size(), 5);
+        assert_eq!(state.tree.as_ref().unwrap().get_deepest(), Some(3));
+
+        // block 3 and its vertices are orphaned and replaced
+        let replaced = chain.reorg(1, vec![vertices(&[1])]);
+        chain.mine(vec![]);
+        let block = chain.mine(vertices(&[3]));
+        let state =
+            advance(Arc::clone(&chain), &state, &head(&chain), U64::zero())
+                .await
+                .unwrap();
+
+        let tree = state.tree.as_ref().unwrap();
+        assert_eq!(tree.size(), 5);
+        assert_eq!(tree.get_vertex(3).unwrap().get_parent(), Some(1));
+        assert_eq!(tree.get_vertex(4).unwrap().get_depth(), 3);
+        assert_eq!(tree.get_deepest(), Some(4));
+        assert_eq!(
+            Some(state.get_provenance(3).unwrap().block_hash),
+            replaced.hash
+        );
+        assert_eq!(
+            Some(state.get_provenance(4).unwrap().block_hash),
+            block.hash
+        );
+        assert_eq!(Some(state.block_hash), block.hash);
+    }
+
+    #[tokio::test]
+    async fn test_advance_recomputes_without_common_ancestor() {
+        let chain = Arc::new(MockChain::new());
+        // the tree starts at block 2, so the vertex of block 1 isn't in it
+        chain.mine(vertices(&[0]));
+        chain.mine(vertices(&[0]));
+        chain.mine(vertices(&[0]));
+        let state = sync(&chain, 2).await;
+        assert_eq!(state.tree.as_ref().unwrap().size(), 2);
+
+        let block = chain.reorg(2, vec![vertices(&[0, 0, 1])]);
+        let state =
+            advance(Arc::clone(&chain), &state, &head(&chain), U64::from(2))
+                .await
+                .unwrap();
+
+        let tree = state.tree.as_ref().unwrap();
+        assert_eq!(tree.size(), 3);
+        assert_eq!(tree.get_deepest(), Some(2));
+        assert_eq!(
+            Some(state.get_provenance(0).unwrap().block_hash),
+            block.hash
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use crate::error::*;
+use crate::tree_lib::Tree;
+
+use ethers::providers::Middleware;
+use ethers::types::{Address, BlockId, H256, U256};
+use ethers::utils::keccak256;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use snafu::ResultExt;
+use std::collections::HashMap;
+
+/// Storage layout of `Tree.TreeCtx` starting at `base_slot`:
+///
+/// - `base_slot`: `deepestVertex`, `deepestDepth` and `verticesLength`, packed
+///   as `uint32`s from the lowest order bytes
+/// - `base_slot + 1`: `vertices` mapping, each `Vertex` taking two slots from
+///   `keccak256(index . (base_slot + 1))`: `depth` and `ancestorsLength`
+///   packed, then the `ancestors` mapping
+///
+/// `batchSetAncestors` stores the depths of the ancestors of a vertex in its
+/// `ancestors`, the parent's depth first, and not their indices. Storage thus
+/// holds the depth of every vertex but none of the parents.
+#[derive(Clone, Debug)]
+pub struct TreeCtxLayout {
+    pub base_slot: U256,
+}
+
+impl TreeCtxLayout {
+    pub fn new(base_slot: U256) -> Self {
+        TreeCtxLayout { base_slot }
+    }
+
+    /// slot with `deepestVertex`, `deepestDepth` and `verticesLength`
+    pub fn header_slot(&self) -> H256 {
+        u256_to_h256(self.base_slot)
+    }
+
+    /// first slot of vertex `index`, holding `depth` and `ancestorsLength`
+    pub fn vertex_slot(&self, index: u32) -> U256 {
+        mapping_slot(U256::from(index), self.base_slot + 1)
+    }
+
+    /// slot holding ancestors `[8 * key, 8 * key + 8)` of vertex `index`
+    pub fn ancestors_slot(&self, index: u32, key: u32) -> H256 {
+        let ancestors = self.vertex_slot(index) + 1;
+        u256_to_h256(mapping_slot(U256::from(key), ancestors))
+    }
+}
+
+/// Rebuilds the tree stored at `base_slot` of contract `address` from raw
+/// storage slots, without querying any logs. Up to `concurrency` vertices are
+/// read at a time.
+///
+/// Parents are not stored, so the parent of a vertex is recovered only when
+/// a single vertex inserted before it is one level up. Trees with two
+/// vertices at the same depth before a deeper one fail with
+/// `ParentAmbiguous`, and have to be rebuilt from their logs.
+pub async fn load_tree_from_storage<M: Middleware + 'static>(
+    access: &M,
+    address: Address,
+    base_slot: U256,
+    block: Option<BlockId>,
+    concurrency: usize,
+) -> Result<Option<Tree>> {
+    let layout = TreeCtxLayout::new(base_slot);
+
+    let header =
+        read_slot(access, address, layout.header_slot(), block).await?;
+    let deepest_vertex = packed_u32(header, 0);
+    let deepest_depth = packed_u32(header, 1);
+    let vertices_length = packed_u32(header, 2);
+
+    if vertices_length == 0 {
+        return Ok(None);
+    }
+
+    let depths: Vec<u32> = stream::iter(0..vertices_length)
+        .map(|index| read_depth(access, address, &layout, index, block))
+        .buffered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    // vertices inserted so far at each depth: the last one and their count
+    let mut at_depth: HashMap<u32, (u32, usize)> = HashMap::new();
+    let mut tree = Tree::default();
+    for (index, depth) in depths.into_iter().enumerate() {
+        let index = index as u32;
+        let parent = match (index, at_depth.get(&depth.wrapping_sub(1))) {
+            (0, _) => 0,
+            (_, Some((parent, 1))) => *parent,
+            (_, Some((_, candidates))) => {
+                return ParentAmbiguous {
+                    index,
+                    depth: depth - 1,
+                    candidates: *candidates,
+                }
+                .fail()
+            }
+            (_, None) => {
+                return AncestorNotFound {
+                    index,
+                    depth: depth.saturating_sub(1),
+                }
+                .fail()
+            }
+        };
+
+        tree = tree.insert_vertex(parent)?;
+        let inserted_depth = tree.get_vertex(index).unwrap().get_depth();
+        if inserted_depth != depth {
+            return DepthMismatch {
+                index,
+                stored_depth: depth,
+                depth: inserted_depth,
+            }
+            .fail();
+        }
+
+        let entry = at_depth.entry(depth).or_insert((index, 0));
+        *entry = (index, entry.1 + 1);
+    }
+
+    let deepest = tree.get_deepest().unwrap();
+    let depth = tree.get_vertex(deepest).unwrap().get_depth();
+    if deepest != deepest_vertex || depth != deepest_depth {
+        return DeepestMismatch {
+            stored_index: deepest_vertex,
+            stored_depth: deepest_depth,
+            index: deepest,
+            depth,
+        }
+        .fail();
+    }
+
+    Ok(Some(tree))
+}
+
+/// Reads the depth of vertex `index`, checking it against the depth of its
+/// parent, the first of its `ancestors`
+async fn read_depth<M: Middleware + 'static>(
+    access: &M,
+    address: Address,
+    layout: &TreeCtxLayout,
+    index: u32,
+    block: Option<BlockId>,
+) -> Result<u32> {
+    let slot = u256_to_h256(layout.vertex_slot(index));
+    let vertex = read_slot(access, address, slot, block).await?;
+    let depth = packed_u32(vertex, 0);
+    let ancestors_length = packed_u32(vertex, 1);
+
+    if index == 0 {
+        return Ok(depth);
+    }
+
+    if ancestors_length == 0 {
+        return AncestorNotFound {
+            index,
+            depth: depth.saturating_sub(1),
+        }
+        .fail();
+    }
+
+    let ancestors =
+        read_slot(access, address, layout.ancestors_slot(index, 0), block)
+            .await?;
+    let parent_depth = packed_u32(ancestors, 0);
+    if parent_depth.checked_add(1) != Some(depth) {
+        return DepthMismatch {
+            index,
+            stored_depth: depth,
+            depth: parent_depth.saturating_add(1),
+        }
+        .fail();
+    }
+
+    Ok(depth)
+}
+
+async fn read_slot<M: Middleware + 'static>(
+    access: &M,
+    address: Address,
+    slot: H256,
+    block: Option<BlockId>,
+) -> Result<U256> {
+    let value = access
+        .get_storage_at(address, slot, block)
+        .await
+        .map_err(|e| e.into())
+        .context(TreeUnavailable {
+            err: format!("Error reading storage slot {:?}", slot),
+        })?;
+
+    Ok(U256::from_big_endian(value.as_bytes()))
+}
+
+/// `offset`-th `uint32` packed in `value`, from the lowest order bytes
+fn packed_u32(value: U256, offset: usize) -> u32 {
+    ((value >> (offset * 32)) & U256::from(u32::MAX)).as_u32()
+}
+
+/// Slot of `key` in the mapping at `slot`
+fn mapping_slot(key: U256, slot: U256) -> U256 {
+    let mut preimage = [0u8; 64];
+    key.to_big_endian(&mut preimage[..32]);
+    slot.to_big_endian(&mut preimage[32..]);
+    U256::from_big_endian(&keccak256(preimage))
+}
+
+fn u256_to_h256(value: U256) -> H256 {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    H256::from(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_chain::MockChain;
+
+    fn pack(values: &[u32]) -> U256 {
+        values.iter().enumerate().fold(
+            U256::zero(),
+            |packed, (offset, value)| {
+                packed | (U256::from(*value) << (offset * 32))
+            },
+        )
+    }
+
+    /// Depths `batchSetAncestors` stores in the ancestors of a vertex at
+    /// `depth`: the parent's, then the ones with its trailing ones cleared
+    fn required_depths(depth: u32) -> Vec<u32> {
+        let mut ancestor = depth - 1;
+        let count = 1 + ancestor.trailing_ones();
+        (0..count)
+            .map(|i| {
+                let required = ancestor;
+                ancestor &= u32::MAX << (i + 1);
+                required
+            })
+            .collect()
+    }
+
+    /// Lays out the tree built from `parents` at `base_slot` of `address` as
+    /// `Tree.insertVertex` does
+    fn store(
+        chain: &MockChain,
+        address: Address,
+        parents: &[u32],
+        base_slot: U256,
+    ) {
+        let layout = TreeCtxLayout::new(base_slot);
+        let mut tree = Tree::default();
+
+        for (index, parent) in parents.iter().enumerate() {
+            let index = index as u32;
+            tree = tree.insert_vertex(*parent).unwrap();
+            let depth = tree.get_vertex(index).unwrap().get_depth();
+            let ancestors = if index == 0 {
+                vec![]
+            } else {
+                required_depths(depth)
+            };
+
+            chain.set_storage(
+                address,
+                u256_to_h256(layout.vertex_slot(index)),
+                u256_to_h256(pack(&[depth, ancestors.len() as u32])),
+            );
+            for (key, batch) in ancestors.chunks(8).enumerate() {
+                chain.set_storage(
+                    address,
+                    layout.ancestors_slot(index, key as u32),
+                    u256_to_h256(pack(batch)),
+                );
+            }
+        }
+
+        let deepest = tree.get_deepest().unwrap();
+        let depth = tree.get_vertex(deepest).unwrap().get_depth();
+        chain.set_storage(
+            address,
+            layout.header_slot(),
+            u256_to_h256(pack(&[deepest, depth, parents.len() as u32])),
+        );
+    }
+
+    async fn load(chain: &MockChain, base_slot: U256) -> Result<Option<Tree>> {
+        load_tree_from_storage(chain, Address::zero(), base_slot, None, 4).await
+    }
+
+    #[test]
+    fn test_required_depths() {
+        assert_eq!(required_depths(1), vec![0]);
+        assert_eq!(required_depths(8), vec![7, 6, 4, 0]);
+        assert_eq!(
+            required_depths(0xc0),
+            vec![0xbf, 0xbe, 0xbc, 0xb8, 0xb0, 0xa0, 0x80]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_tree_from_storage() {
+        let chain = MockChain::new();
+        let base_slot = U256::from(3);
+        // a chain forking at its end
+        let mut parents: Vec<u32> =
+            (0..20).map(|i: u32| i.saturating_sub(1)).collect();
+        parents.extend(&[19, 19]);
+        store(&chain, Address::zero(), &parents, base_slot);
+
+        let tree = load(&chain, base_slot)
+            .await
+            .unwrap()
+            .expect("Tree should not be empty");
+
+        assert_eq!(tree.size(), parents.len());
+        for (index, parent) in parents.iter().enumerate().skip(1) {
+            assert_eq!(
+                tree.get_vertex(index as u32).unwrap().get_parent(),
+                Some(*parent)
+            );
+        }
+        assert_eq!(tree.get_deepest(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_load_empty_tree_from_storage() {
+        let chain = MockChain::new();
+        let tree = load(&chain, U256::zero()).await.unwrap();
+        assert!(tree.is_none(), "Empty storage should have no tree");
+    }
+
+    #[tokio::test]
+    async fn test_load_tree_with_ambiguous_parent() {
+        let chain = MockChain::new();
+        // vertices 1 and 2 are both at depth 1 when 3 is inserted
+        store(&chain, Address::zero(), &[0, 0, 0, 2], U256::zero());
+
+        assert!(matches!(
+            load(&chain, U256::zero()).await,
+            Err(Error::ParentAmbiguous {
+                index: 3,
+                depth: 1,
+                candidates: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_load_tree_from_corrupted_storage() {
+        let chain = MockChain::new();
+        let base_slot = U256::zero();
+        store(&chain, Address::zero(), &[0, 0, 1, 2], base_slot);
+
+        let layout = TreeCtxLayout::new(base_slot);
+        chain.set_storage(
+            Address::zero(),
+            u256_to_h256(layout.vertex_slot(3)),
+            u256_to_h256(pack(&[7, 1])),
+        );
+
+        assert!(matches!(
+            load(&chain, base_slot).await,
+            Err(Error::DepthMismatch { index: 3, .. })
+        ));
+    }
+}
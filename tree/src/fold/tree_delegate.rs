@@ -1,7 +1,10 @@
 use crate::error::*;
+use crate::metrics;
 use crate::tree_lib::Tree;
 
-use super::contracts::tree_contract;
+use super::contracts::tree_contract::{self, VertexInsertedFilter};
+use super::log_query::{query_logs, LogQueryConfig};
+use super::verify::{verify_tree, VerifyConfig};
 
 use offchain_core::types::Block;
 use state_fold::{
@@ -10,11 +13,17 @@ use state_fold::{
 };
 
 use async_trait::async_trait;
+use ethers::abi::RawLog;
+use ethers::contract::EthEvent;
 use ethers::providers::Middleware;
-use ethers::types::{Address, U256};
+use ethers::types::{Address, Log, H256, U256, U64};
+use im::HashMap;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, instrument, trace};
 
 /// Tree dlib state, to be passed to and returned by fold.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -23,22 +32,163 @@ pub struct TreeState {
     pub caller_address: Address,
     pub identifier: U256,
     pub tree: Option<Tree>,
+    // on-chain origin of each vertex, keyed by vertex index
+    #[serde(default)]
+    pub provenance: HashMap<u32, VertexProvenance>,
+    // block this state was computed at
+    #[serde(default)]
+    pub block_number: U64,
+    #[serde(default)]
+    pub block_hash: H256,
+    // how the state is fetched, carried from sync to each fold
+    #[serde(skip)]
+    pub config: Arc<FoldConfig>,
+}
+
+/// Initial state of a `TreeState`: the contract owning the tree library
+/// object, the indexed `_id` its `VertexInserted` logs are filtered by, and the
+/// first block to query for them
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize,
+)]
+pub struct TreeKey {
+    pub tree_address: Address,
+    pub identifier: U256,
+    pub from_block: U64,
+}
+
+impl TreeKey {
+    pub fn new(tree_address: Address) -> Self {
+        TreeKey {
+            tree_address,
+            ..Default::default()
+        }
+    }
+}
+
+/// How trees are fetched: the chunking of log queries, the cross-check
+/// against the contract and, when `fetch_senders` is set, a query of the
+/// transaction of each log to record who inserted the vertex. Only the
+/// senders change the folded states.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FoldConfig {
+    pub log_query: LogQueryConfig,
+    pub verify: VerifyConfig,
+    pub fetch_senders: bool,
+}
+
+/// Initial state of a `TreeState`: the tree of `key`, fetched as `config`
+/// sets. States are identified by their `key` alone.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TreeInitialState {
+    pub key: TreeKey,
+    #[serde(skip)]
+    pub config: Arc<FoldConfig>,
+}
+
+impl TreeInitialState {
+    pub fn new(key: TreeKey, config: Arc<FoldConfig>) -> Self {
+        TreeInitialState { key, config }
+    }
+}
+
+impl PartialEq for TreeInitialState {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for TreeInitialState {}
+
+impl Hash for TreeInitialState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state)
+    }
+}
+
+/// Where a vertex was inserted on-chain. Vertices can be ordered by
+/// `(block_number, log_index)` to tell when each appeared relative to others
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct VertexProvenance {
+    pub block_number: U64,
+    pub block_hash: H256,
+    pub tx_hash: H256,
+    pub log_index: U256,
+    // sender of the inserting transaction, when senders are fetched
+    pub from: Option<Address>,
+}
+
+impl TreeState {
+    /// get on-chain origin of vertex by index
+    pub fn get_provenance(&self, index: u32) -> Option<&VertexProvenance> {
+        self.provenance.get(&index)
+    }
+
+    /// Roll back vertices inserted after block `block_number`, tagging the
+    /// state with that block. Vertices are inserted in block order, so the
+    /// tree is truncated at the first vertex past the block.
+    pub fn rewind(&self, block_number: U64, block_hash: H256) -> TreeState {
+        let size = self.tree.as_ref().map(|tree| tree.size()).unwrap_or(0);
+        let keep = (0..size as u32)
+            .find(|index| {
+                self.provenance
+                    .get(index)
+                    .map(|p| p.block_number > block_number)
+                    .unwrap_or(false)
+            })
+            .unwrap_or(size as u32);
+
+        let tree = match &self.tree {
+            Some(tree) if keep > 0 => Some(tree.truncate(keep as usize)),
+            _ => None,
+        };
+        let provenance = self
+            .provenance
+            .clone()
+            .into_iter()
+            .filter(|(index, _)| *index < keep)
+            .collect();
+
+        TreeState {
+            caller_address: self.caller_address,
+            identifier: self.identifier,
+            tree,
+            provenance,
+            block_number,
+            block_hash,
+            config: Arc::clone(&self.config),
+        }
+    }
 }
 
 #[async_trait]
 impl Foldable for TreeState {
-    type InitialState = (U256, Address);
+    type InitialState = TreeInitialState;
     type Error = Error;
 
     async fn sync<M: Middleware + 'static>(
         initial_state: &Self::InitialState,
-        _block: &Block,
-        _env: &StateFoldEnvironment<M>,
+        block: &Block,
+        env: &StateFoldEnvironment<M>,
         access: Arc<SyncMiddleware<M>>,
     ) -> std::result::Result<Self, Self::Error> {
-        let (identifier, caller_address) = *initial_state;
+        let TreeInitialState { key, config } = initial_state;
+        let state = compute_state(
+            Arc::clone(&access),
+            key.tree_address,
+            key.identifier,
+            None,
+            block,
+            Some(key.from_block.max(env.genesis_block)),
+            config,
+        )
+        .await?;
 
-        compute_state(access, caller_address, identifier, None).await
+        verify_state(access, &state, block, &config.verify).await?;
+        Ok(TreeState {
+            config: Arc::clone(config),
+            ..state
+        })
     }
 
     async fn fold<M: Middleware + 'static>(
@@ -47,6 +197,7 @@ impl Foldable for TreeState {
         _env: &StateFoldEnvironment<M>,
         access: Arc<FoldMiddleware<M>>,
     ) -> std::result::Result<Self, Self::Error> {
+        let config = &previous_state.config;
         let identifier = previous_state.identifier;
         let caller_address = previous_state.caller_address;
 
@@ -55,51 +206,235 @@ impl Foldable for TreeState {
         if !(fold_utils::contains_address(&bloom, &caller_address)
             && fold_utils::contains_topic(&bloom, &identifier))
         {
-            return Ok(previous_state.clone());
+            metrics::inc_bloom_skips();
+            trace!(
+                tree = ?caller_address,
+                block_number = %block.number,
+                "Block bloom has no tree event"
+            );
+            return Ok(TreeState {
+                block_number: block.number,
+                block_hash: block.hash,
+                ..previous_state.clone()
+            });
         }
 
-        compute_state(
-            access,
+        let state = compute_state(
+            Arc::clone(&access),
             caller_address,
             identifier,
-            previous_state.tree.clone(),
+            Some(previous_state),
+            block,
+            None,
+            config,
         )
-        .await
+        .await?;
+
+        verify_state(access, &state, block, &config.verify).await?;
+        Ok(state)
     }
 }
 
-/// Computes the state from all events emission
-async fn compute_state<M: Middleware + 'static>(
+/// Cross-checks the state against the contract when verification is enabled
+async fn verify_state<M: Middleware + 'static>(
+    access: Arc<M>,
+    state: &TreeState,
+    block: &Block,
+    config: &VerifyConfig,
+) -> crate::error::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    verify_tree(
+        access,
+        state.caller_address,
+        &state.tree,
+        block.hash,
+        config,
+    )
+    .await
+}
+
+/// Computes the state at `block` from all events emission, querying logs in
+/// chunks set by `config` from `from_block` up to `block` when it is given,
+/// and the sender of each vertex when `config` fetches senders
+#[instrument(
+    level = "debug",
+    skip(access, previous_state, block, config),
+    fields(block_number = %block.number)
+)]
+pub(crate) async fn compute_state<M: Middleware + 'static>(
     access: Arc<M>,
     caller_address: Address,
     identifier: U256,
-    previous_tree: Option<Tree>,
+    previous_state: Option<&TreeState>,
+    block: &Block,
+    from_block: Option<U64>,
+    config: &FoldConfig,
 ) -> crate::error::Result<TreeState> {
-    let contract = tree_contract::Tree::new(caller_address, access);
+    let start = Instant::now();
+    let contract =
+        tree_contract::Tree::new(caller_address, Arc::clone(&access));
+    let filter = contract.vertex_inserted_filter().topic1(identifier).filter;
 
     // Get all inserted events.
-    let inserted_events = contract
-        .vertex_inserted_filter()
-        .topic1(identifier)
-        .query()
-        .await
-        .map_err(|e| e.into())
-        .context(TreeUnavailable {
-            err: format!("Error querying for vertex inserted"),
-        })?;
-
-    let tree = inserted_events.into_iter().try_fold(
-        previous_tree,
-        |tree, event| {
-            tree.unwrap_or_default()
-                .insert_vertex(event.parent)
-                .map(|tree| Some(tree))
-        },
-    )?;
+    let logs = match from_block {
+        Some(from_block) => {
+            query_logs(
+                access.as_ref(),
+                &filter,
+                from_block,
+                block.number,
+                &config.log_query,
+            )
+            .await
+        }
+        None => {
+            metrics::inc_rpc_calls("eth_getLogs");
+            access.get_logs(&filter).await
+        }
+    }
+    .map_err(|e| e.into())
+    .context(TreeUnavailable {
+        err: format!("Error querying for vertex inserted"),
+    })?;
+    metrics::inc_logs_fetched(logs.len());
+    debug!(logs = logs.len(), "Fetched vertex inserted logs");
+
+    let fetch_senders = config.fetch_senders;
+    let mut senders: std::collections::HashMap<H256, Option<Address>> =
+        std::collections::HashMap::new();
+
+    let mut tree = previous_state.and_then(|state| state.tree.clone());
+    let mut provenance = previous_state
+        .map(|state| state.provenance.clone())
+        .unwrap_or_default();
+
+    for log in logs {
+        let event = decode_vertex_inserted(&log)?;
+        let new_tree = tree.unwrap_or_default().insert_vertex(event.parent)?;
+
+        let tx_hash = log.transaction_hash.unwrap_or_default();
+        let from = if fetch_senders {
+            match senders.get(&tx_hash) {
+                Some(from) => *from,
+                None => {
+                    metrics::inc_rpc_calls("eth_getTransactionByHash");
+                    let from = access
+                        .get_transaction(tx_hash)
+                        .await
+                        .map_err(|e| e.into())
+                        .context(TreeUnavailable {
+                            err: format!("Error querying for transaction"),
+                        })?
+                        .map(|tx| tx.from);
+                    senders.insert(tx_hash, from);
+                    from
+                }
+            }
+        } else {
+            None
+        };
+
+        // the vertex just inserted is always the last one
+        let index = new_tree.get_last().unwrap();
+        provenance.insert(
+            index,
+            VertexProvenance {
+                block_number: log.block_number.unwrap_or_default(),
+                block_hash: log.block_hash.unwrap_or_default(),
+                tx_hash,
+                log_index: log.log_index.unwrap_or_default(),
+                from,
+            },
+        );
+
+        tree = Some(new_tree);
+    }
 
+    metrics::observe_compute_state(start.elapsed());
     Ok(TreeState {
         caller_address,
         identifier,
         tree,
+        provenance,
+        block_number: block.number,
+        block_hash: block.hash,
+        config: previous_state
+            .map(|state| Arc::clone(&state.config))
+            .unwrap_or_default(),
     })
 }
+
+/// Decode a raw `VertexInserted` log
+pub(crate) fn decode_vertex_inserted(
+    log: &Log,
+) -> crate::error::Result<VertexInsertedFilter> {
+    let raw_log = RawLog {
+        topics: log.topics.clone(),
+        data: log.data.to_vec(),
+    };
+
+    VertexInsertedFilter::decode_log(&raw_log).map_err(|e| {
+        Error::LogMalformed {
+            block_number: log.block_number.unwrap_or_default(),
+            log_index: log.log_index.unwrap_or_default(),
+            err: e.to_string(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc_record::ReplayClient;
+
+    use ethers::providers::Provider;
+    use std::path::Path;
+    use std::str::FromStr;
+
+    /// Replay of a `compute_state` run recorded with `--record-rpc`
+    fn replay(fixture: &str) -> Arc<Provider<ReplayClient>> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(fixture);
+        Arc::new(Provider::new(ReplayClient::open(&path).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_compute_state_replay() {
+        let block = Block {
+            hash: H256::from_low_u64_be(16),
+            number: U64::from(16),
+            parent_hash: H256::from_low_u64_be(15),
+            timestamp: U256::zero(),
+            logs_bloom: Default::default(),
+        };
+
+        let state = compute_state(
+            replay("compute_state.ndjson"),
+            Address::from_str("5fbdb2315678afecb367f032d93f642f64180aa3")
+                .unwrap(),
+            U256::zero(),
+            None,
+            &block,
+            Some(U64::zero()),
+            &Default::default(),
+        )
+        .await
+        .expect("Recorded run should compute the tree");
+
+        let tree = state.tree.as_ref().unwrap();
+        assert_eq!(tree.size(), 5);
+        assert_eq!(tree.get_deepest(), Some(4));
+        assert_eq!(tree.get_vertex(4).unwrap().get_depth(), 3);
+        assert_eq!(tree.get_vertex(3).unwrap().get_parent(), Some(1));
+
+        // vertices inserted in the same block keep their log order
+        let provenance = state.get_provenance(2).unwrap();
+        assert_eq!(provenance.block_number, U64::from(5));
+        assert_eq!(provenance.log_index, U256::one());
+        assert_eq!(state.block_hash, block.hash);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::error::*;
+use crate::metrics;
+use crate::tree_lib::Tree;
+
+use ethers::abi::{parse_abi, Detokenize, Tokenize};
+use ethers::contract::Contract;
+use ethers::providers::Middleware;
+use ethers::types::{Address, BlockId, H256, U256};
+use snafu::ResultExt;
+use std::sync::Arc;
+use tracing::error;
+
+/// View functions the tree owner contract is expected to expose, as
+/// `TestTree` does
+const TREE_VIEW_ABI: [&str; 4] = [
+    "function getTreeSize() view returns (uint256)",
+    "function getDeepest() view returns (uint256, uint256)",
+    "function getDepth(uint256) view returns (uint256)",
+    "function getAncestorAtDepth(uint256, uint256) view returns (uint256)",
+];
+
+/// Cross-check of the off-chain tree against the contract after each sync or
+/// fold. Up to `sample_size` vertices have their depth and an ancestor checked
+/// on top of the tree size and deepest vertex.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifyConfig {
+    pub enabled: bool,
+    pub sample_size: usize,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        VerifyConfig {
+            enabled: false,
+            sample_size: 8,
+        }
+    }
+}
+
+/// Pick up to `sample_size` vertices spread evenly over the tree, always
+/// including the last one
+pub fn sample_vertices(size: usize, sample_size: usize) -> Vec<u32> {
+    if size == 0 || sample_size == 0 {
+        return vec![];
+    }
+
+    let count = sample_size.min(size);
+    let mut indices: Vec<u32> = (0..count)
+        .map(|i| ((size - 1) * (i + 1) / count) as u32)
+        .collect();
+    indices.dedup();
+    indices
+}
+
+/// Check `tree` against the view functions of the contract at
+/// `caller_address`, as of block `block_hash`. Mismatches are counted in the
+/// metrics of the contract.
+pub async fn verify_tree<M: Middleware + 'static>(
+    access: Arc<M>,
+    caller_address: Address,
+    tree: &Option<Tree>,
+    block_hash: H256,
+    config: &VerifyConfig,
+) -> Result<()> {
+    let abi = parse_abi(&TREE_VIEW_ABI).unwrap();
+    let contract = Contract::new(caller_address, abi, access);
+    let block = BlockId::Hash(block_hash);
+
+    let size = tree.as_ref().map(|tree| tree.size()).unwrap_or_default();
+    let onchain_size: U256 =
+        view_call(&contract, "getTreeSize", (), block).await?;
+    check(
+        caller_address,
+        block_hash,
+        onchain_size == U256::from(size),
+        || format!("tree size {} on-chain, {} off-chain", onchain_size, size),
+    )?;
+
+    let tree = match tree {
+        Some(tree) if size > 0 => tree,
+        _ => return Ok(()),
+    };
+
+    let deepest = tree.get_deepest().unwrap();
+    let deepest_depth = tree.get_vertex(deepest).unwrap().get_depth();
+    let (onchain_deepest, onchain_depth): (U256, U256) =
+        view_call(&contract, "getDeepest", (), block).await?;
+    check(
+        caller_address,
+        block_hash,
+        onchain_deepest == U256::from(deepest)
+            && onchain_depth == U256::from(deepest_depth),
+        || {
+            format!(
+                "deepest vertex {} at depth {} on-chain, {} at depth {} \
+                 off-chain",
+                onchain_deepest, onchain_depth, deepest, deepest_depth
+            )
+        },
+    )?;
+
+    for index in sample_vertices(size, config.sample_size) {
+        let depth = tree.get_vertex(index).unwrap().get_depth();
+        let onchain_depth: U256 =
+            view_call(&contract, "getDepth", U256::from(index), block).await?;
+        check(
+            caller_address,
+            block_hash,
+            onchain_depth == U256::from(depth),
+            || {
+                format!(
+                    "vertex {} at depth {} on-chain, {} off-chain",
+                    index, onchain_depth, depth
+                )
+            },
+        )?;
+
+        let ancestor_depth = depth / 2;
+        let ancestor = tree.get_ancestor_rc_at(index, ancestor_depth)?;
+        let onchain_ancestor: U256 = view_call(
+            &contract,
+            "getAncestorAtDepth",
+            (U256::from(index), U256::from(ancestor_depth)),
+            block,
+        )
+        .await?;
+        check(
+            caller_address,
+            block_hash,
+            onchain_ancestor == U256::from(ancestor.get_index()),
+            || {
+                format!(
+                    "ancestor of {} at depth {} is {} on-chain, {} off-chain",
+                    index,
+                    ancestor_depth,
+                    onchain_ancestor,
+                    ancestor.get_index()
+                )
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+async fn view_call<M, T, D>(
+    contract: &Contract<M>,
+    name: &str,
+    args: T,
+    block: BlockId,
+) -> Result<D>
+where
+    M: Middleware + 'static,
+    T: Tokenize,
+    D: Detokenize,
+{
+    contract
+        .method::<T, D>(name, args)
+        .map_err(|e| e.into())
+        .context(TreeUnavailable {
+            err: format!("Error encoding call to {}", name),
+        })?
+        .block(block)
+        .call()
+        .await
+        .map_err(|e| e.into())
+        .context(TreeUnavailable {
+            err: format!("Error calling {}", name),
+        })
+}
+
+fn check<F: FnOnce() -> String>(
+    caller_address: Address,
+    block_hash: H256,
+    matches: bool,
+    describe: F,
+) -> Result<()> {
+    if matches {
+        Ok(())
+    } else {
+        metrics::inc_divergences(&caller_address);
+        let err = describe();
+        error!(
+            ?caller_address,
+            ?block_hash,
+            "Tree diverges from on-chain state: {}",
+            err
+        );
+        TreeDivergent { block_hash, err }.fail()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_chain::MockChain;
+
+    use ethers::abi::{self, Token};
+
+    fn tree() -> Tree {
+        Tree::default()
+            .insert_vertex(0)
+            .unwrap()
+            .insert_vertex(0)
+            .unwrap()
+            .insert_vertex(1)
+            .unwrap()
+    }
+
+    /// Answer the view call `name` with `args` to the contract at `address`
+    fn set_view(
+        chain: &MockChain,
+        address: Address,
+        name: &str,
+        args: &[u64],
+        output: &[u64],
+    ) {
+        let abi = parse_abi(&TREE_VIEW_ABI).unwrap();
+        let tokens = |values: &[u64]| -> Vec<Token> {
+            values.iter().map(|v| Token::Uint(U256::from(*v))).collect()
+        };
+        let data = abi
+            .function(name)
+            .unwrap()
+            .encode_input(&tokens(args))
+            .unwrap();
+
+        chain.set_call(
+            address,
+            data.into(),
+            abi::encode(&tokens(output)).into(),
+        );
+    }
+
+    /// Contract at `address` holding the tree of `tree()`, with
+    /// `onchain_size` vertices
+    fn contract(address: Address, onchain_size: u64) -> Arc<MockChain> {
+        let chain = Arc::new(MockChain::new());
+        set_view(&chain, address, "getTreeSize", &[], &[onchain_size]);
+        set_view(&chain, address, "getDeepest", &[], &[2, 2]);
+        for (index, depth, ancestor) in &[(0, 0, 0), (1, 1, 0), (2, 2, 1)] {
+            set_view(&chain, address, "getDepth", &[*index], &[*depth]);
+            set_view(
+                &chain,
+                address,
+                "getAncestorAtDepth",
+                &[*index, *depth / 2],
+                &[*ancestor],
+            );
+        }
+        chain
+    }
+
+    #[test]
+    fn test_sample_vertices() {
+        assert!(sample_vertices(0, 8).is_empty(), "Empty tree has no sample");
+        assert_eq!(sample_vertices(3, 8), vec![0, 1, 2]);
+        assert_eq!(sample_vertices(100, 4), vec![24, 49, 74, 99]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_tree() {
+        let address = Address::from_low_u64_be(0x7ee);
+        let config = VerifyConfig::default();
+
+        let matching = contract(address, 3);
+        verify_tree(matching, address, &Some(tree()), H256::zero(), &config)
+            .await
+            .expect("Tree should match its contract");
+
+        let divergent = contract(address, 4);
+        assert!(matches!(
+            verify_tree(
+                divergent,
+                address,
+                &Some(tree()),
+                H256::zero(),
+                &config
+            )
+            .await,
+            Err(Error::TreeDivergent { .. })
+        ));
+        assert!(metrics::encode().contains(&format!(
+            "tree_divergences_total{{tree=\"{:?}\"}} 1",
+            address
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_verify_call_error() {
+        let address = Address::from_low_u64_be(0x7ef);
+        let result = verify_tree(
+            Arc::new(MockChain::new()),
+            address,
+            &Some(tree()),
+            H256::zero(),
+            &VerifyConfig::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::TreeUnavailable { .. })));
+    }
+}
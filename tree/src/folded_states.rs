@@ -0,0 +1,458 @@
+use crate::fold::reorg::{advance, block_of};
+use crate::fold::tree_delegate::{
+    FoldConfig, TreeInitialState, TreeKey, TreeState,
+};
+use crate::metrics;
+
+use state_fold::{types::QueryBlock, Foldable, StateFoldEnvironment};
+
+use ethers::providers::{Middleware, Provider, ProviderError, PubsubClient};
+use ethers::types::{Block, BlockNumber, H256, U64};
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Folded states buffered for each subscriber before it starts lagging
+const SUBSCRIPTION_CAPACITY: usize = 16;
+
+/// Trees folded by default before the least recently requested is evicted
+pub const DEFAULT_MAX_TREES: usize = 1024;
+
+/// Trees folded at once on each new head
+const FOLD_CONCURRENCY: usize = 16;
+
+/// Latest folded state of the trees followed so far, keyed by the
+/// `TreeState` initial state. Kept up to date by `follow_new_heads`, so
+/// requests can be answered without querying the chain. Each new state is
+/// also broadcast to the subscribers of its tree.
+///
+/// Trees are folded `safety_margin` blocks behind each new head, the block
+/// `Latest` queries resolve to. Once more than `max_trees` trees besides the
+/// ones tracked from startup are folded, the least recently requested one is
+/// evicted, unless it is tracked or has subscribers.
+#[derive(Debug)]
+pub struct FoldedStates {
+    states: RwLock<HashMap<TreeKey, TreeState>>,
+    subscribers: Mutex<HashMap<TreeKey, broadcast::Sender<TreeState>>>,
+    // trees tracked from startup not synced yet
+    pending: Mutex<HashSet<TreeKey>>,
+    // trees tracked from startup, never evicted
+    tracked: Mutex<HashSet<TreeKey>>,
+    // last request of each folded tree, by request count
+    last_used: Mutex<HashMap<TreeKey, u64>>,
+    requests: AtomicU64,
+    max_trees: usize,
+    // blocks behind the head trees are folded at
+    safety_margin: usize,
+    // latest head the trees were folded for
+    head: Mutex<Option<U64>>,
+}
+
+impl Default for FoldedStates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FoldedStates {
+    pub fn new() -> Self {
+        Self::with_max_trees(DEFAULT_MAX_TREES)
+    }
+
+    /// Folded states evicting trees once more than `max_trees` untracked ones
+    /// are folded
+    pub fn with_max_trees(max_trees: usize) -> Self {
+        FoldedStates {
+            states: Default::default(),
+            subscribers: Default::default(),
+            pending: Default::default(),
+            tracked: Default::default(),
+            last_used: Default::default(),
+            requests: Default::default(),
+            max_trees,
+            safety_margin: 0,
+            head: Default::default(),
+        }
+    }
+
+    /// Fold trees `safety_margin` blocks behind each new head
+    pub fn with_safety_margin(self, safety_margin: usize) -> Self {
+        FoldedStates {
+            safety_margin,
+            ..self
+        }
+    }
+
+    /// get latest folded state of tree
+    pub async fn get(&self, key: &TreeKey) -> Option<TreeState> {
+        let state = self.states.read().await.get(key).cloned();
+        if state.is_some() {
+            let request = self.requests.fetch_add(1, Ordering::Relaxed);
+            self.last_used.lock().unwrap().insert(*key, request);
+        }
+        state
+    }
+
+    /// get latest folded state of tree, without counting it as requested
+    pub async fn peek(&self, key: &TreeKey) -> Option<TreeState> {
+        self.states.read().await.get(key).cloned()
+    }
+
+    /// number of the latest head trees were folded at, if any
+    pub fn head(&self) -> Option<U64> {
+        *self.head.lock().unwrap()
+    }
+
+    /// set latest folded state of tree, tracking it from now on
+    pub async fn insert(&self, key: TreeKey, state: TreeState) {
+        metrics::observe_tree(&key, &state);
+        {
+            let mut states = self.states.write().await;
+            states.insert(key, state.clone());
+            let request = self.requests.fetch_add(1, Ordering::Relaxed);
+            self.last_used.lock().unwrap().entry(key).or_insert(request);
+
+            let tracked = self.tracked.lock().unwrap().len();
+            if states.len() > self.max_trees + tracked {
+                if let Some(evicted) = self.least_recently_used() {
+                    debug!(tree = ?evicted.tree_address, "Evicting tree");
+                    states.remove(&evicted);
+                    self.last_used.lock().unwrap().remove(&evicted);
+                    metrics::forget_tree(&evicted);
+                }
+            }
+        }
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(sender) = subscribers.get(&key) {
+            // drop the channel once every subscriber is gone
+            if sender.send(state).is_err() {
+                subscribers.remove(&key);
+            }
+        }
+    }
+
+    /// receive every state folded for tree from now on
+    pub fn subscribe(&self, key: TreeKey) -> broadcast::Receiver<TreeState> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| broadcast::channel(SUBSCRIPTION_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// whether tree is tracked from startup or has subscribers, the trees
+    /// folded on demand
+    pub fn is_followed(&self, key: &TreeKey) -> bool {
+        self.tracked.lock().unwrap().contains(key)
+            || has_subscribers(&self.subscribers.lock().unwrap(), key)
+    }
+
+    /// Least recently requested tree that can be evicted: neither tracked
+    /// from startup nor subscribed to
+    fn least_recently_used(&self) -> Option<TreeKey> {
+        let tracked = self.tracked.lock().unwrap();
+        let subscribers = self.subscribers.lock().unwrap();
+
+        self.last_used
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| !tracked.contains(key))
+            .filter(|(key, _)| !has_subscribers(&subscribers, key))
+            .min_by_key(|(_, last_used)| **last_used)
+            .map(|(key, _)| *key)
+    }
+
+    /// initial states of all tracked trees
+    pub async fn keys(&self) -> Vec<TreeKey> {
+        self.states.read().await.keys().cloned().collect()
+    }
+
+    /// number of trees tracked from startup not synced yet
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// mark trees as tracked from startup but not synced yet
+    pub(crate) fn set_pending(&self, keys: &[TreeKey]) {
+        self.pending.lock().unwrap().extend(keys.iter().cloned());
+    }
+
+    /// initial states of the trees tracked from startup
+    pub fn tracked_keys(&self) -> Vec<TreeKey> {
+        self.tracked.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// mark trees as tracked from startup, never evicted
+    pub(crate) fn set_tracked(&self, keys: &[TreeKey]) {
+        self.tracked.lock().unwrap().extend(keys.iter().cloned());
+    }
+
+    /// Sync the latest state of every tree of `keys` in the background, as
+    /// `config` sets, and track them from then on. Trees failing to sync are
+    /// retried every `retry_interval`, and are pending until they are synced.
+    pub fn track<M: Middleware + 'static>(
+        self: &Arc<Self>,
+        env: Arc<StateFoldEnvironment<M>>,
+        config: Arc<FoldConfig>,
+        keys: Vec<TreeKey>,
+        retry_interval: Duration,
+    ) -> JoinHandle<()> {
+        self.set_pending(&keys);
+        self.set_tracked(&keys);
+        let states = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let mut pending = keys;
+
+            loop {
+                let synced = join_all(pending.iter().map(|key| {
+                    TreeState::get_state_for_block(
+                        &TreeInitialState::new(*key, Arc::clone(&config)),
+                        QueryBlock::Latest,
+                        &env,
+                    )
+                }))
+                .await;
+
+                let mut failed = vec![];
+                for (key, result) in pending.into_iter().zip(synced) {
+                    match result {
+                        Ok(block_state) => {
+                            info!(
+                                tree = ?key.tree_address,
+                                block_number = %block_state.state.block_number,
+                                "Tree synced"
+                            );
+                            states.insert(key, block_state.state).await;
+                            states.pending.lock().unwrap().remove(&key);
+                        }
+                        Err(e) => {
+                            warn!(
+                                tree = ?key.tree_address,
+                                "Failed to sync tree, retrying in {:?}: {}",
+                                retry_interval,
+                                e
+                            );
+                            failed.push(key);
+                        }
+                    }
+                }
+
+                if failed.is_empty() {
+                    return;
+                }
+                pending = failed;
+                tokio::time::sleep(retry_interval).await;
+            }
+        })
+    }
+
+    /// Fold every tracked tree up to `safety_margin` blocks behind the new
+    /// `head`, as its latest state was fetched, up to `FOLD_CONCURRENCY`
+    /// trees at once. A block on top of a tree's latest state is folded by
+    /// the environment, while a tree whose latest state is not the block's
+    /// parent, after a reorg or missed heads, is advanced from it, rewinding
+    /// the vertices of orphaned blocks. A tree that fails to fold keeps its
+    /// previous state and is retried on the next head.
+    pub async fn fold_all<M: Middleware + 'static>(
+        &self,
+        provider: &Arc<M>,
+        env: &StateFoldEnvironment<M>,
+        head: &Block<H256>,
+    ) {
+        let head_number = match head.number {
+            Some(number) => number,
+            None => return,
+        };
+        let target = if self.safety_margin == 0 {
+            Some(head.clone())
+        } else {
+            let number =
+                head_number.saturating_sub(U64::from(self.safety_margin));
+            match provider.get_block(BlockNumber::Number(number)).await {
+                Ok(block) => block,
+                Err(e) => {
+                    warn!(block_number = %number, "Failed to get block: {}", e);
+                    None
+                }
+            }
+        };
+        let target = match target.as_ref().and_then(block_of) {
+            Some(target) => target,
+            None => return,
+        };
+
+        let latest: Vec<(TreeKey, TreeState)> = self
+            .states
+            .read()
+            .await
+            .iter()
+            .map(|(key, state)| (*key, state.clone()))
+            .collect();
+
+        let target = &target;
+        stream::iter(latest)
+            .filter(|(_, state)| {
+                futures::future::ready(state.block_hash != target.hash)
+            })
+            .for_each_concurrent(FOLD_CONCURRENCY, |(key, state)| async move {
+                let folded = if state.block_hash == target.parent_hash {
+                    TreeState::get_state_for_block(
+                        &TreeInitialState::new(key, Arc::clone(&state.config)),
+                        QueryBlock::BlockHash(target.hash),
+                        env,
+                    )
+                    .await
+                    .map(|block_state| block_state.state)
+                    .map_err(|e| e.to_string())
+                } else {
+                    let from_block = key.from_block.max(env.genesis_block);
+                    advance(Arc::clone(provider), &state, target, from_block)
+                        .await
+                        .map_err(|e| e.to_string())
+                };
+
+                match folded {
+                    Ok(state) => self.insert(key, state).await,
+                    Err(e) => warn!(
+                        tree = ?key.tree_address,
+                        block_hash = ?target.hash,
+                        "Failed to fold tree: {}",
+                        e
+                    ),
+                }
+            })
+            .await;
+
+        // trees that failed to fold now lag behind the head
+        *self.head.lock().unwrap() = Some(head_number);
+    }
+}
+
+/// Poll the node for its latest block every `interval` and fold every
+/// tracked tree ahead of time on each new one, for nodes without
+/// subscriptions
+pub async fn poll_new_heads<M: Middleware + 'static>(
+    provider: Arc<M>,
+    env: Arc<StateFoldEnvironment<M>>,
+    states: Arc<FoldedStates>,
+    interval: Duration,
+) {
+    let mut last = None;
+
+    loop {
+        match provider.get_block(BlockNumber::Latest).await {
+            Ok(Some(head)) if head.hash.is_some() && head.hash != last => {
+                debug!(
+                    block_number = ?head.number,
+                    "Folding trees at new head"
+                );
+                states.fold_all(&provider, &env, &head).await;
+                last = head.hash;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to poll the latest block: {}", e),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Subscribe to new chain heads and fold every tracked tree ahead of time
+/// on each of them, until the subscription ends
+pub async fn follow_new_heads<P: PubsubClient + 'static>(
+    provider: Arc<Provider<P>>,
+    env: Arc<StateFoldEnvironment<Provider<P>>>,
+    states: Arc<FoldedStates>,
+) -> Result<(), ProviderError> {
+    let mut heads = provider.subscribe_blocks().await?;
+
+    while let Some(head) = heads.next().await {
+        debug!(block_number = ?head.number, "Folding trees at new head");
+        states.fold_all(&provider, &env, &head).await;
+    }
+
+    warn!("New heads subscription ended");
+    Ok(())
+}
+
+fn has_subscribers(
+    subscribers: &HashMap<TreeKey, broadcast::Sender<TreeState>>,
+    key: &TreeKey,
+) -> bool {
+    subscribers
+        .get(key)
+        .map_or(false, |sender| sender.receiver_count() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(address: u64) -> TreeKey {
+        TreeKey::new(ethers::types::Address::from_low_u64_be(address))
+    }
+
+    fn state() -> TreeState {
+        TreeState {
+            caller_address: Default::default(),
+            identifier: Default::default(),
+            tree: None,
+            provenance: Default::default(),
+            block_number: U64::zero(),
+            block_hash: H256::zero(),
+            config: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evict_least_recently_used() {
+        let states = FoldedStates::with_max_trees(2);
+        states.insert(key(1), state()).await;
+        states.insert(key(2), state()).await;
+        states.get(&key(1)).await;
+
+        states.insert(key(3), state()).await;
+        assert!(states.get(&key(2)).await.is_none(), "2 was evicted");
+        assert!(states.get(&key(1)).await.is_some());
+        assert!(states.get(&key(3)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_keep_tracked_and_subscribed_trees() {
+        let states = FoldedStates::with_max_trees(2);
+        states.tracked.lock().unwrap().insert(key(1));
+        states.insert(key(1), state()).await;
+        states.insert(key(2), state()).await;
+        let _subscription = states.subscribe(key(2));
+
+        states.insert(key(3), state()).await;
+        states.insert(key(4), state()).await;
+        assert!(states.get(&key(1)).await.is_some(), "1 is tracked");
+        assert!(states.get(&key(2)).await.is_some(), "2 has a subscriber");
+        assert!(states.get(&key(3)).await.is_none(), "3 was evicted");
+        assert!(states.get(&key(4)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_is_followed() {
+        let states = FoldedStates::new();
+        states.tracked.lock().unwrap().insert(key(1));
+        assert!(states.is_followed(&key(1)));
+        assert!(!states.is_followed(&key(2)));
+
+        let subscription = states.subscribe(key(2));
+        assert!(states.is_followed(&key(2)));
+        drop(subscription);
+        assert!(!states.is_followed(&key(2)), "2 lost its subscriber");
+    }
+}
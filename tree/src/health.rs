@@ -0,0 +1,292 @@
+use crate::folded_states::FoldedStates;
+
+use ethers::providers::Middleware;
+use ethers::types::{Address, U256, U64};
+use snafu::Snafu;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{info, warn};
+
+/// Services reported through `grpc.health.v1`, besides the whole server
+const SERVICES: [&str; 2] =
+    ["StateServer.DelegateManager", "TreeServer.TreeQuery"];
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum NotReady {
+    #[snafu(display("Ethereum node unreachable: {}", err))]
+    NodeUnreachable { err: String },
+    #[snafu(display("Connected to chain {} instead of {}", actual, expected))]
+    WrongChain { expected: U256, actual: U256 },
+    #[snafu(display(
+        "Tree {:?} is {} blocks behind the safe chain head",
+        tree,
+        lag
+    ))]
+    TreeLagging { tree: Address, lag: u64 },
+    #[snafu(display("{} trees tracked from startup not synced yet", trees))]
+    TreesSyncing { trees: usize },
+}
+
+/// Conditions for the server to be ready: the node must answer, be on
+/// `chain_id` if set, and every tree tracked from startup must be synced
+/// and at most `max_tree_lag` blocks behind the latest block considered
+/// final, `safety_margin` blocks behind the head. They are checked every
+/// `check_interval`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthConfig {
+    pub chain_id: Option<u64>,
+    pub max_tree_lag: u64,
+    pub check_interval: Duration,
+    pub safety_margin: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            chain_id: None,
+            max_tree_lag: 10,
+            check_interval: Duration::from_secs(10),
+            safety_margin: 0,
+        }
+    }
+}
+
+/// Check whether the server can answer requests with up to date trees
+pub async fn check_readiness<M: Middleware>(
+    access: &M,
+    config: &HealthConfig,
+    folded_states: Option<&FoldedStates>,
+) -> Result<(), NotReady> {
+    let head = access.get_block_number().await.map_err(|e| {
+        NotReady::NodeUnreachable {
+            err: format!("{}", e),
+        }
+    })?;
+
+    if let Some(expected) = config.chain_id {
+        let actual = access.get_chainid().await.map_err(|e| {
+            NotReady::NodeUnreachable {
+                err: format!("{}", e),
+            }
+        })?;
+
+        if actual != U256::from(expected) {
+            return WrongChain {
+                expected: U256::from(expected),
+                actual,
+            }
+            .fail();
+        }
+    }
+
+    if let Some(folded_states) = folded_states {
+        let pending = folded_states.pending();
+        if pending > 0 {
+            return TreesSyncing { trees: pending }.fail();
+        }
+
+        // trees followed on demand fold on their own schedule, and only the
+        // tracked ones, synced by now, must be up to date
+        let safe_head = head.saturating_sub(U64::from(config.safety_margin));
+        for key in folded_states.tracked_keys() {
+            if let Some(state) = folded_states.peek(&key).await {
+                let lag = safe_head.saturating_sub(state.block_number).as_u64();
+                if lag > config.max_tree_lag {
+                    return TreeLagging {
+                        tree: key.tree_address,
+                        lag,
+                    }
+                    .fail();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Report the server as not serving until it is ready, then keep the
+/// `grpc.health.v1` status of the server and its services in line with its
+/// readiness
+pub async fn report_health<M: Middleware>(
+    mut reporter: HealthReporter,
+    access: Arc<M>,
+    config: HealthConfig,
+    folded_states: Option<Arc<FoldedStates>>,
+) {
+    let mut was_ready = None;
+
+    reporter
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+    for service in SERVICES.iter() {
+        reporter
+            .set_service_status(service, ServingStatus::NotServing)
+            .await;
+    }
+
+    loop {
+        let readiness =
+            check_readiness(&*access, &config, folded_states.as_deref()).await;
+        let ready = readiness.is_ok();
+
+        if was_ready != Some(ready) {
+            match &readiness {
+                Ok(()) => info!("Tree server is ready"),
+                Err(e) => warn!("Tree server is not ready: {}", e),
+            }
+
+            let status = if ready {
+                ServingStatus::Serving
+            } else {
+                ServingStatus::NotServing
+            };
+            reporter.set_service_status("", status).await;
+            for service in SERVICES.iter() {
+                reporter.set_service_status(service, status).await;
+            }
+
+            was_ready = Some(ready);
+        }
+
+        tokio::time::sleep(config.check_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fold::tree_delegate::{TreeKey, TreeState};
+    use crate::mock_chain::{MockChain, CHAIN_ID};
+
+    use ethers::types::H256;
+
+    /// Node at block `head`, or down if `head` is unset
+    fn node(head: Option<u64>) -> MockChain {
+        let chain = MockChain::new();
+        match head {
+            Some(head) => {
+                chain.mine_empty(head as usize);
+            }
+            None => chain.set_unreachable(true),
+        }
+        chain
+    }
+
+    fn state_at(block_number: u64) -> TreeState {
+        TreeState {
+            caller_address: Address::zero(),
+            identifier: U256::zero(),
+            tree: None,
+            provenance: im::HashMap::new(),
+            block_number: U64::from(block_number),
+            block_hash: H256::from_low_u64_be(block_number),
+            config: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_checks() {
+        let config = HealthConfig {
+            chain_id: Some(CHAIN_ID),
+            ..Default::default()
+        };
+
+        assert!(check_readiness(&node(Some(100)), &config, None)
+            .await
+            .is_ok());
+        assert!(matches!(
+            check_readiness(&node(None), &config, None).await,
+            Err(NotReady::NodeUnreachable { .. })
+        ));
+
+        let other_chain = HealthConfig {
+            chain_id: Some(CHAIN_ID + 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_readiness(&node(Some(100)), &other_chain, None).await,
+            Err(NotReady::WrongChain { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tree_lag() {
+        let config = HealthConfig::default();
+        let folded_states = FoldedStates::new();
+        folded_states.set_tracked(&[TreeKey::default()]);
+        folded_states.insert(TreeKey::default(), state_at(95)).await;
+
+        assert!(check_readiness(
+            &node(Some(100)),
+            &config,
+            Some(&folded_states)
+        )
+        .await
+        .is_ok());
+        assert!(matches!(
+            check_readiness(&node(Some(120)), &config, Some(&folded_states))
+                .await,
+            Err(NotReady::TreeLagging { lag: 25, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tree_lag_with_safety_margin() {
+        let config = HealthConfig {
+            safety_margin: 5,
+            ..Default::default()
+        };
+        let folded_states = FoldedStates::new();
+        folded_states.set_tracked(&[TreeKey::default()]);
+        folded_states
+            .insert(TreeKey::default(), state_at(105))
+            .await;
+
+        // 15 blocks behind the head, 10 behind the latest final block
+        assert!(check_readiness(
+            &node(Some(120)),
+            &config,
+            Some(&folded_states)
+        )
+        .await
+        .is_ok());
+        assert!(matches!(
+            check_readiness(&node(Some(121)), &config, Some(&folded_states))
+                .await,
+            Err(NotReady::TreeLagging { lag: 11, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_untracked_tree_lag() {
+        let config = HealthConfig::default();
+        let folded_states = FoldedStates::new();
+        folded_states.insert(TreeKey::default(), state_at(95)).await;
+
+        // trees folded for subscribers don't hold back readiness
+        assert!(check_readiness(
+            &node(Some(120)),
+            &config,
+            Some(&folded_states)
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_trees_syncing() {
+        let config = HealthConfig::default();
+        let folded_states = FoldedStates::new();
+        folded_states.set_pending(&[TreeKey::default()]);
+
+        assert!(matches!(
+            check_readiness(&node(Some(100)), &config, Some(&folded_states))
+                .await,
+            Err(NotReady::TreesSyncing { trees: 1 })
+        ));
+    }
+}
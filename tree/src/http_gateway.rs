@@ -0,0 +1,509 @@
+//! HTTP/JSON gateway to the `TreeQuery` service, for clients that can't
+//! speak gRPC. Every route is answered by the same handlers as the gRPC
+//! service:
+//!
+//! - `GET /trees/{address}`: `GetState`, version 2
+//! - `GET /trees/{address}/size`: `GetTreeSize`
+//! - `GET /trees/{address}/deepest`: `GetDeepest`
+//! - `GET /trees/{address}/vertices/{i}`: `GetVertex`
+//! - `GET /trees/{address}/vertices/{i}/ancestor?depth=d`:
+//!   `GetAncestorAtDepth`
+//! - `GET /trees/{address}/vertices/{i}/valid?distance=d`:
+//!   `IsValidVertexWithDistance`
+//!
+//! Every route takes at most one of the `block`, `block_hash` or
+//! `confirmations` query parameters. A `pos_instance` parameter is accepted
+//! and ignored. Errors are returned as `{"error": message}`, with the HTTP
+//! status of their gRPC code: a missing vertex is `404`, a malformed or
+//! divergent tree is `500`, an unreachable node is `503` and an invalid
+//! request is `400`.
+
+use crate::deadline::with_deadline;
+use crate::initial_state::LATEST_VERSION;
+use crate::logging::in_request_span;
+use crate::metrics;
+use crate::tree_query_server::proto::block_selector::Selector;
+use crate::tree_query_server::proto::tree_query_server::TreeQuery;
+use crate::tree_query_server::proto::{
+    self, BlockSelector, GetAncestorAtDepthRequest, GetDeepestRequest,
+    GetTreeSizeRequest, GetVertexRequest, IsValidVertexWithDistanceRequest,
+    TreeLocator,
+};
+use crate::tree_query_server::{parse_block_selector, parse_locator};
+use crate::tree_server::TreeDelegateManager;
+
+use ethers::providers::Middleware;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Server, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::{Code, Request, Status};
+
+/// Serve the gateway on `address` until `shutdown` completes
+pub async fn serve_http<M: Middleware + 'static>(
+    manager: Arc<TreeDelegateManager<M>>,
+    address: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let manager = Arc::clone(&manager);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(Arc::clone(&manager), request)
+            }))
+        }
+    });
+
+    Server::try_bind(&address)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    State,
+    Size,
+    Deepest,
+    Vertex(u32),
+    Ancestor { index: u32, depth: u32 },
+    IsValid { index: u32, distance: u32 },
+}
+
+#[derive(Debug, PartialEq)]
+struct GatewayRequest {
+    tree: TreeLocator,
+    block: Option<BlockSelector>,
+    route: Route,
+}
+
+async fn handle<M: Middleware + 'static>(
+    manager: Arc<TreeDelegateManager<M>>,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let result = if request.method() != Method::GET {
+        Err(Status::new(Code::Unimplemented, "Only GET is supported"))
+    } else {
+        match parse_request(request.uri().path(), request.uri().query()) {
+            Ok(request) => respond(&manager, request).await,
+            Err(e) => Err(e),
+        }
+    };
+
+    Ok(match result {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(status) => json_response(
+            http_status(status.code()),
+            json!({ "error": status.message() }).to_string(),
+        ),
+    })
+}
+
+async fn respond<M: Middleware + 'static>(
+    manager: &TreeDelegateManager<M>,
+    request: GatewayRequest,
+) -> Result<String, Status> {
+    let GatewayRequest { tree, block, route } = request;
+    let tree = Some(tree);
+
+    match route {
+        Route::State => {
+            let start = Instant::now();
+            let timeout = manager.request_timeout;
+            let result = in_request_span(
+                "GetState",
+                with_deadline(timeout, async move {
+                    let key = parse_locator(tree)?;
+                    let query_block = parse_block_selector(block)?;
+                    let (_, json_state) = manager
+                        .json_state_at(key, query_block, LATEST_VERSION)
+                        .await?;
+                    Ok(json_state.to_string())
+                }),
+            )
+            .await;
+
+            let code = result.as_ref().err().map_or(Code::Ok, Status::code);
+            metrics::observe_request("GetState", code, start.elapsed());
+            result
+        }
+
+        Route::Size => {
+            let response = manager
+                .get_tree_size(Request::new(GetTreeSizeRequest { tree, block }))
+                .await?
+                .into_inner();
+
+            Ok(json!({
+                "block": response.block.as_ref().map(block_json),
+                "size": response.size,
+            })
+            .to_string())
+        }
+
+        Route::Deepest => {
+            let response = manager
+                .get_deepest(Request::new(GetDeepestRequest { tree, block }))
+                .await?
+                .into_inner();
+
+            Ok(json!({
+                "block": response.block.as_ref().map(block_json),
+                "deepest": response.deepest.as_ref().map(vertex_json),
+            })
+            .to_string())
+        }
+
+        Route::Vertex(index) => {
+            let response = manager
+                .get_vertex(Request::new(GetVertexRequest {
+                    tree,
+                    block,
+                    index,
+                }))
+                .await?
+                .into_inner();
+
+            Ok(json!({
+                "block": response.block.as_ref().map(block_json),
+                "vertex": response.vertex.as_ref().map(vertex_json),
+            })
+            .to_string())
+        }
+
+        Route::Ancestor { index, depth } => {
+            let response = manager
+                .get_ancestor_at_depth(Request::new(
+                    GetAncestorAtDepthRequest {
+                        tree,
+                        block,
+                        index,
+                        depth,
+                    },
+                ))
+                .await?
+                .into_inner();
+
+            Ok(json!({
+                "block": response.block.as_ref().map(block_json),
+                "ancestor": response.ancestor.as_ref().map(vertex_json),
+            })
+            .to_string())
+        }
+
+        Route::IsValid { index, distance } => {
+            let response = manager
+                .is_valid_vertex_with_distance(Request::new(
+                    IsValidVertexWithDistanceRequest {
+                        tree,
+                        block,
+                        index,
+                        distance,
+                    },
+                ))
+                .await?
+                .into_inner();
+
+            Ok(json!({
+                "block": response.block.as_ref().map(block_json),
+                "valid": response.valid,
+            })
+            .to_string())
+        }
+    }
+}
+
+/// Parse the request path and query string into a `TreeQuery` request
+fn parse_request(
+    path: &str,
+    query: Option<&str>,
+) -> Result<GatewayRequest, Status> {
+    let params: HashMap<String, String> =
+        url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (address, route) = match segments.as_slice() {
+        ["trees", address] => (address, Route::State),
+        ["trees", address, "size"] => (address, Route::Size),
+        ["trees", address, "deepest"] => (address, Route::Deepest),
+        ["trees", address, "vertices", index] => {
+            (address, Route::Vertex(parse_number("index", index)?))
+        }
+        ["trees", address, "vertices", index, "ancestor"] => (
+            address,
+            Route::Ancestor {
+                index: parse_number("index", index)?,
+                depth: required_param(&params, "depth")?,
+            },
+        ),
+        ["trees", address, "vertices", index, "valid"] => (
+            address,
+            Route::IsValid {
+                index: parse_number("index", index)?,
+                distance: required_param(&params, "distance")?,
+            },
+        ),
+        _ => {
+            return Err(Status::new(
+                Code::NotFound,
+                format!("No route for `{}`", path),
+            ))
+        }
+    };
+
+    Ok(GatewayRequest {
+        tree: TreeLocator {
+            tree_address: address.to_string(),
+            pos_instance: params
+                .get("pos_instance")
+                .cloned()
+                .unwrap_or_default(),
+        },
+        block: parse_block_params(&params)?,
+        route,
+    })
+}
+
+fn parse_block_params(
+    params: &HashMap<String, String>,
+) -> Result<Option<BlockSelector>, Status> {
+    let mut selectors = vec![];
+
+    if let Some(number) = params.get("block") {
+        selectors.push(Selector::Number(parse_number("block", number)?));
+    }
+    if let Some(hash) = params.get("block_hash") {
+        selectors.push(Selector::Hash(hash.clone()));
+    }
+    if let Some(confirmations) = params.get("confirmations") {
+        selectors.push(Selector::Confirmations(parse_number(
+            "confirmations",
+            confirmations,
+        )?));
+    }
+
+    if selectors.len() > 1 {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "Only one of `block`, `block_hash` or `confirmations` is allowed",
+        ));
+    }
+
+    Ok(selectors.pop().map(|selector| BlockSelector {
+        selector: Some(selector),
+    }))
+}
+
+fn required_param(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> Result<u32, Status> {
+    let value = params.get(name).ok_or_else(|| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Missing query parameter `{}`", name),
+        )
+    })?;
+
+    parse_number(name, value)
+}
+
+fn parse_number<T: std::str::FromStr>(
+    name: &str,
+    value: &str,
+) -> Result<T, Status> {
+    value.parse().map_err(|_| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Invalid `{}`: `{}` is not a number", name, value),
+        )
+    })
+}
+
+/// HTTP status of a gRPC code, following the mapping of grpc-gateway
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::Unknown | Code::Internal | Code::DataLoss => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn block_json(block: &proto::Block) -> Value {
+    json!({ "number": block.number, "hash": block.hash })
+}
+
+fn vertex_json(vertex: &proto::Vertex) -> Value {
+    json!({
+        "index": vertex.index,
+        "depth": vertex.depth,
+        "parent": vertex.parent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        TreeServerConfig, TreeServerFileConfig, TreeServerOpt,
+    };
+    use crate::mock_chain::{vertex_inserted, MockChain};
+
+    use ethers::types::{Address, U256};
+
+    const ADDRESS: &str = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+
+    fn route(path: &str, query: Option<&str>) -> Result<Route, Code> {
+        parse_request(path, query)
+            .map(|request| request.route)
+            .map_err(|status| status.code())
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let trees = format!("/trees/{}", ADDRESS);
+
+        assert_eq!(route(&trees, None), Ok(Route::State));
+        assert_eq!(route(&format!("{}/size", trees), None), Ok(Route::Size));
+        assert_eq!(
+            route(&format!("{}/deepest/", trees), None),
+            Ok(Route::Deepest)
+        );
+        assert_eq!(
+            route(&format!("{}/vertices/3", trees), None),
+            Ok(Route::Vertex(3))
+        );
+        assert_eq!(
+            route(&format!("{}/vertices/3/ancestor", trees), Some("depth=1")),
+            Ok(Route::Ancestor { index: 3, depth: 1 })
+        );
+        assert_eq!(
+            route(&format!("{}/vertices/3/valid", trees), Some("distance=2")),
+            Ok(Route::IsValid {
+                index: 3,
+                distance: 2
+            })
+        );
+
+        assert_eq!(route("/trees", None), Err(Code::NotFound));
+        assert_eq!(
+            route(&format!("{}/vertices/x", trees), None),
+            Err(Code::InvalidArgument)
+        );
+        assert_eq!(
+            route(&format!("{}/vertices/3/ancestor", trees), None),
+            Err(Code::InvalidArgument),
+            "Ancestor requires a depth"
+        );
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let request = parse_request(
+            &format!("/trees/{}/size", ADDRESS),
+            Some("pos_instance=0x2a&confirmations=6"),
+        )
+        .unwrap();
+
+        assert_eq!(request.tree.tree_address, ADDRESS);
+        assert_eq!(request.tree.pos_instance, "0x2a");
+        assert_eq!(
+            request.block,
+            Some(BlockSelector {
+                selector: Some(Selector::Confirmations(6)),
+            })
+        );
+
+        assert!(
+            parse_request(
+                &format!("/trees/{}/size", ADDRESS),
+                Some("block=10&block_hash=0x00"),
+            )
+            .is_err(),
+            "Block selectors are exclusive"
+        );
+    }
+
+    /// Status and JSON body of a `GET` of `path` on a chain with a tree of
+    /// two vertices at `tree`
+    async fn get(tree: Address, path: &str) -> (StatusCode, Value) {
+        let chain = Arc::new(MockChain::new());
+        chain.mine(vec![
+            vertex_inserted(tree, U256::zero(), 0),
+            vertex_inserted(tree, U256::zero(), 0),
+        ]);
+        let config = TreeServerConfig::merge(
+            TreeServerOpt::default(),
+            TreeServerFileConfig::default(),
+        )
+        .unwrap();
+        let manager = Arc::new(TreeDelegateManager::new(chain, &config, None));
+
+        let request = hyper::Request::get(path).body(Body::empty()).unwrap();
+        let response = handle(manager, request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_vertex_route() {
+        let tree = Address::from_low_u64_be(0x7ee);
+
+        let (status, json) =
+            get(tree, &format!("/trees/{:?}/vertices/1", tree)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json["vertex"],
+            json!({ "index": 1, "depth": 1, "parent": 0 })
+        );
+        assert_eq!(json["block"]["number"], 1);
+
+        let (status, json) =
+            get(tree, &format!("/trees/{:?}/vertices/5", tree)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(json["error"].is_string());
+    }
+
+    #[test]
+    fn test_http_status() {
+        assert_eq!(http_status(Code::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(http_status(Code::InvalidArgument), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            http_status(Code::DataLoss),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            http_status(Code::Unavailable),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Computations in flight by key. Concurrent calls for the same key wait for
+/// a single computation and share its result, which is dropped once it is
+/// handed out, so later calls compute it again. When a computation fails or
+/// is cancelled, the next waiting call runs its own.
+#[derive(Debug)]
+pub struct InFlight<K, V> {
+    calls: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K, V> Default for InFlight<K, V> {
+    fn default() -> Self {
+        InFlight {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Clone> InFlight<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Result of the computation in flight for `key`, running `compute` if
+    /// there is none
+    pub async fn get_or_try_run<F, Fut, E>(
+        &self,
+        key: K,
+        compute: F,
+    ) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let cell =
+            Arc::clone(self.calls.lock().unwrap().entry(key).or_default());
+
+        let result = cell.get_or_try_init(compute).await.map(V::clone);
+
+        // the calls still waiting hold the cell
+        let mut calls = self.calls.lock().unwrap();
+        if calls
+            .get(&key)
+            .map_or(false, |call| Arc::ptr_eq(call, &cell))
+        {
+            calls.remove(&key);
+        }
+
+        result
+    }
+
+    /// Number of keys with a computation in flight
+    pub fn len(&self) -> usize {
+        self.calls.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    async fn run(
+        in_flight: &InFlight<u64, u64>,
+        key: u64,
+        computed: &AtomicUsize,
+    ) -> Result<u64, ()> {
+        in_flight
+            .get_or_try_run(key, || async {
+                computed.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(key * 2)
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let in_flight = InFlight::new();
+        let computed = AtomicUsize::new(0);
+
+        let (first, second, other) = tokio::join!(
+            run(&in_flight, 1, &computed),
+            run(&in_flight, 1, &computed),
+            run(&in_flight, 2, &computed)
+        );
+        assert_eq!((first, second, other), (Ok(2), Ok(2), Ok(4)));
+        assert_eq!(computed.load(Ordering::SeqCst), 2);
+        assert!(in_flight.is_empty());
+
+        // results are not kept once handed out
+        run(&in_flight, 1, &computed).await.unwrap();
+        assert_eq!(computed.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_failure() {
+        let in_flight = InFlight::<u64, u64>::new();
+
+        let failed = in_flight
+            .get_or_try_run(1, || async { Err("unavailable") })
+            .await;
+        assert!(failed.is_err());
+        assert!(in_flight.is_empty());
+
+        let retried = in_flight
+            .get_or_try_run(1, || async { Ok::<_, &str>(2) })
+            .await;
+        assert_eq!(retried, Ok(2));
+    }
+}
@@ -0,0 +1,456 @@
+//! Initial state JSON of `GetState` requests.
+//!
+//! Version 1, the default, is the original object with a `tree_address` and
+//! a `pos_instance`:
+//!
+//! ```json
+//! { "pos_instance": 0, "tree_address": "0x..." }
+//! ```
+//!
+//! Version 2 adds optional fields, all numbers being either JSON numbers or
+//! decimal or `0x` prefixed hex strings:
+//!
+//! ```json
+//! {
+//!     "version": 2,
+//!     "tree_address": "0x...",
+//!     "from_block": "0xe4e1c0",
+//!     "block": { "confirmations": 6 },
+//!     "event": { "id": 0 }
+//! }
+//! ```
+//!
+//! - `from_block` is the first block queried for `VertexInserted` logs,
+//! - `block` selects the block of the state: `"latest"`, or an object with
+//!   one of `number`, `hash` or `confirmations` behind the latest block,
+//! - `event.id` is the indexed `_id` of the `VertexInserted` logs.
+//!
+//! `pos_instance` is the index of the caller's instance, such as a dispute
+//! game, and not the `_id` of the tree. It is accepted in both versions and
+//! ignored, the tree being selected by `tree_address` and `event.id` alone.
+
+use crate::fold::tree_delegate::TreeKey;
+
+use ethers::types::{Address, H256, U256, U64};
+use ethers::utils::keccak256;
+use serde_json::{Map, Value};
+use snafu::{ResultExt, Snafu};
+use state_fold::types::QueryBlock;
+use std::str::FromStr;
+use tonic::{Code, Status};
+
+pub const LATEST_VERSION: u64 = 2;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum InitialStateError {
+    #[snafu(display("Invalid initial state JSON: {}", source))]
+    InvalidJson { source: serde_json::Error },
+    #[snafu(display("Invalid initial state field `{}`: {}", field, err))]
+    InvalidField { field: String, err: String },
+}
+
+impl From<InitialStateError> for Status {
+    fn from(error: InitialStateError) -> Self {
+        Status::new(Code::InvalidArgument, format!("{}", error))
+    }
+}
+
+pub type InitialStateResult<T> = std::result::Result<T, InitialStateError>;
+
+/// Parsed initial state: the tree and the block to get its state at, if
+/// selected
+pub struct InitialState {
+    pub version: u64,
+    pub key: TreeKey,
+    pub block: Option<QueryBlock>,
+}
+
+/// Parse and validate the initial state JSON of a `GetState` request
+pub fn parse_initial_state(json: &str) -> InitialStateResult<InitialState> {
+    let object: Map<String, Value> =
+        serde_json::from_str(json).context(InvalidJson)?;
+
+    let version = match object.get("version") {
+        Some(version) => parse_u64("version", version)?,
+        None => 1,
+    };
+    let allowed: &[&str] = match version {
+        1 => &["pos_instance", "tree_address"],
+        LATEST_VERSION => &[
+            "version",
+            "pos_instance",
+            "tree_address",
+            "from_block",
+            "block",
+            "event",
+        ],
+        _ => {
+            return InvalidField {
+                field: "version",
+                err: format!(
+                    "unsupported version {}, expected 1 to {}",
+                    version, LATEST_VERSION
+                ),
+            }
+            .fail()
+        }
+    };
+    reject_unknown("", &object, allowed)?;
+
+    if let Some(pos_instance) = object.get("pos_instance") {
+        parse_number("pos_instance", pos_instance)?;
+    }
+
+    let tree_address = match object.get("tree_address") {
+        Some(address) => parse_address("tree_address", address)?,
+        None => {
+            return InvalidField {
+                field: "tree_address",
+                err: "missing",
+            }
+            .fail()
+        }
+    };
+
+    let from_block = match object.get("from_block") {
+        Some(from_block) => U64::from(parse_u64("from_block", from_block)?),
+        None => U64::zero(),
+    };
+
+    let identifier = match object.get("event") {
+        Some(Value::Object(event)) => {
+            reject_unknown("event", event, &["id"])?;
+            match event.get("id") {
+                Some(id) => parse_number("event.id", id)?,
+                None => U256::zero(),
+            }
+        }
+        Some(_) => {
+            return InvalidField {
+                field: "event",
+                err: "expected an object",
+            }
+            .fail()
+        }
+        None => U256::zero(),
+    };
+
+    let block = match object.get("block") {
+        Some(block) => Some(parse_block(block)?),
+        None => None,
+    };
+
+    Ok(InitialState {
+        version,
+        key: TreeKey {
+            tree_address,
+            identifier,
+            from_block,
+        },
+        block,
+    })
+}
+
+fn parse_block(block: &Value) -> InitialStateResult<QueryBlock> {
+    let selector = match block {
+        Value::String(latest) if latest == "latest" => {
+            return Ok(QueryBlock::Latest)
+        }
+        Value::Object(selector) if selector.len() == 1 => selector,
+        _ => {
+            return InvalidField {
+                field: "block",
+                err: "expected `\"latest\"` or an object with one of \
+                      `number`, `hash` or `confirmations`",
+            }
+            .fail()
+        }
+    };
+    reject_unknown("block", selector, &["number", "hash", "confirmations"])?;
+
+    let (name, value) = selector.iter().next().unwrap();
+    match name.as_str() {
+        "number" => Ok(QueryBlock::BlockNumber(U64::from(parse_u64(
+            "block.number",
+            value,
+        )?))),
+        "hash" => Ok(QueryBlock::BlockHash(parse_hash("block.hash", value)?)),
+        _ => Ok(QueryBlock::BlockDepth(
+            parse_u64("block.confirmations", value)? as usize,
+        )),
+    }
+}
+
+fn reject_unknown(
+    parent: &str,
+    object: &Map<String, Value>,
+    allowed: &[&str],
+) -> InitialStateResult<()> {
+    match object.keys().find(|key| !allowed.contains(&key.as_str())) {
+        Some(key) => InvalidField {
+            field: field_path(parent, key),
+            err: format!("unknown field, expected one of {:?}", allowed),
+        }
+        .fail(),
+        None => Ok(()),
+    }
+}
+
+fn field_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+/// Parse a JSON number, or a decimal or `0x` prefixed hex string
+pub fn parse_number(field: &str, value: &Value) -> InitialStateResult<U256> {
+    let number = match value {
+        Value::Number(number) => number.as_u64().map(U256::from),
+        Value::String(number) => match number.strip_prefix("0x") {
+            Some(hex) if !hex.is_empty() && hex.len() <= 64 => {
+                U256::from_str(hex).ok()
+            }
+            Some(_) => None,
+            None => U256::from_dec_str(number).ok(),
+        },
+        _ => None,
+    };
+
+    match number {
+        Some(number) => Ok(number),
+        None => InvalidField {
+            field,
+            err: format!(
+                "expected a non-negative integer, decimal or hex, got {}",
+                value
+            ),
+        }
+        .fail(),
+    }
+}
+
+/// Parse a number as `parse_number` does, up to `u64::MAX`
+pub fn parse_u64(field: &str, value: &Value) -> InitialStateResult<u64> {
+    let number = parse_number(field, value)?;
+
+    if number > U256::from(u64::MAX) {
+        return InvalidField {
+            field,
+            err: format!("{} is too large", number),
+        }
+        .fail();
+    }
+
+    Ok(number.as_u64())
+}
+
+/// Parse a `0x` prefixed address, checking its EIP-55 checksum when it has
+/// mixed case
+pub fn parse_address(
+    field: &str,
+    value: &Value,
+) -> InitialStateResult<Address> {
+    let text = match value {
+        Value::String(text) => text,
+        _ => {
+            return InvalidField {
+                field,
+                err: format!("expected an address string, got {}", value),
+            }
+            .fail()
+        }
+    };
+
+    let hex = text.strip_prefix("0x").unwrap_or(text);
+    let address = match (hex.len(), Address::from_str(hex)) {
+        (40, Ok(address)) => address,
+        _ => {
+            return InvalidField {
+                field,
+                err: format!("`{}` is not a 20 bytes hex address", text),
+            }
+            .fail()
+        }
+    };
+
+    let mixed_case = hex.chars().any(|c| c.is_ascii_lowercase())
+        && hex.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case && hex != &checksum(&address)[2..] {
+        return InvalidField {
+            field,
+            err: format!("`{}` has an invalid EIP-55 checksum", text),
+        }
+        .fail();
+    }
+
+    Ok(address)
+}
+
+fn parse_hash(field: &str, value: &Value) -> InitialStateResult<H256> {
+    let hex = value
+        .as_str()
+        .map(|text| text.strip_prefix("0x").unwrap_or(text));
+
+    match hex.map(|hex| (hex.len(), H256::from_str(hex))) {
+        Some((64, Ok(hash))) => Ok(hash),
+        _ => InvalidField {
+            field,
+            err: format!("expected a 32 bytes hex hash, got {}", value),
+        }
+        .fail(),
+    }
+}
+
+/// EIP-55 mixed case representation of `address`
+pub fn checksum(address: &Address) -> String {
+    let hex = format!("{:x}", address);
+    let hash = keccak256(hex.as_bytes());
+
+    let checksummed: String = hex
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble =
+                (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0xf;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    format!("0x{}", checksummed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_of(json: &str) -> String {
+        match parse_initial_state(json) {
+            Err(InitialStateError::InvalidField { field, .. }) => field,
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("`{}` should be invalid", json),
+        }
+    }
+
+    #[test]
+    fn test_version_1() {
+        let state = parse_initial_state(
+            r#"{"pos_instance": "0x2a", "tree_address":
+                "0x0000000000000000000000000000000000000001"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(state.version, 1);
+        assert_eq!(state.key, TreeKey::new(Address::from_low_u64_be(1)));
+        assert!(state.block.is_none());
+
+        assert_eq!(
+            field_of(
+                r#"{"tree_address": "0x0000000000000000000000000000000000000001",
+                    "from_block": 1}"#
+            ),
+            "from_block",
+            "Version 1 has no `from_block`"
+        );
+    }
+
+    #[test]
+    fn test_version_2() {
+        let state = parse_initial_state(
+            r#"{
+                "version": 2,
+                "tree_address": "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+                "from_block": "15000000",
+                "block": {"confirmations": "0x6"},
+                "event": {"id": 3}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(state.version, 2);
+        assert_eq!(state.key.from_block, U64::from(15_000_000));
+        assert_eq!(state.key.identifier, U256::from(3));
+        assert!(matches!(state.block, Some(QueryBlock::BlockDepth(6))));
+
+        let state = parse_initial_state(
+            r#"{"version": 2, "block": {"number": 100}, "tree_address":
+                "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"}"#,
+        )
+        .unwrap();
+        assert_eq!(state.key.identifier, U256::zero());
+        assert!(matches!(
+            state.block,
+            Some(QueryBlock::BlockNumber(number)) if number == U64::from(100)
+        ));
+
+        let state = parse_initial_state(&format!(
+            r#"{{"version": 2, "block": {{"hash": "{:?}"}}, "tree_address":
+                "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"}}"#,
+            H256::from_low_u64_be(7)
+        ))
+        .unwrap();
+        assert!(matches!(
+            state.block,
+            Some(QueryBlock::BlockHash(hash)) if hash == H256::from_low_u64_be(7)
+        ));
+    }
+
+    #[test]
+    fn test_field_errors() {
+        assert_eq!(field_of(r#"{"version": 2}"#), "tree_address");
+        assert_eq!(field_of(r#"{"version": 3}"#), "version");
+        assert_eq!(
+            field_of(
+                r#"{"tree_address": "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD"}"#
+            ),
+            "tree_address",
+            "Bad checksum"
+        );
+        assert_eq!(
+            field_of(r#"{"tree_address": "0x5aaeb6053f3e94c9b9a09f"}"#),
+            "tree_address"
+        );
+        assert_eq!(
+            field_of(
+                r#"{"version": 2, "from_block": -1, "tree_address":
+                    "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"}"#
+            ),
+            "from_block"
+        );
+        assert_eq!(
+            field_of(
+                r#"{"version": 2, "block": {"number": 1, "hash": "0x00"},
+                    "tree_address": "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"}"#
+            ),
+            "block"
+        );
+        assert_eq!(
+            field_of(
+                r#"{"version": 2, "event": {"topic": 1}, "tree_address":
+                    "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"}"#
+            ),
+            "event.topic"
+        );
+        assert!(matches!(
+            parse_initial_state("{"),
+            Err(InitialStateError::InvalidJson { .. })
+        ));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let address =
+            Address::from_str("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed")
+                .unwrap();
+        assert_eq!(
+            checksum(&address),
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+        );
+    }
+}
@@ -22,7 +22,24 @@
 // Apache v2 license.
 
 #![warn(unused_extern_crates)]
+pub mod config;
+pub mod deadline;
 pub mod error;
+pub mod failover;
 pub mod fold;
+pub mod folded_states;
+pub mod health;
+pub mod http_gateway;
+pub mod in_flight;
+pub mod initial_state;
+pub mod log_dump;
+pub mod logging;
+pub mod metrics;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock_chain;
+pub mod response_cache;
+pub mod rpc_record;
 pub mod tree_lib;
+pub mod tree_query_server;
 pub mod tree_server;
+pub mod tree_updates;
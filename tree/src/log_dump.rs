@@ -0,0 +1,335 @@
+//! Trees rebuilt offline from a dump of their `VertexInserted` logs, for the
+//! `tree` CLI.
+//!
+//! A dump is either the logs of an `eth_getLogs` call, as a JSON array or as
+//! the whole JSON-RPC response, or the parent of each vertex in insertion
+//! order, as a JSON array or separated by whitespace or commas. The parent of
+//! the root vertex is ignored.
+
+use crate::error::Error;
+use crate::fold::contracts::tree_contract::VertexInsertedFilter;
+use crate::fold::tree_delegate::{decode_vertex_inserted, VertexProvenance};
+use crate::tree_lib::Tree;
+
+use ethers::contract::EthEvent;
+use ethers::types::{Address, Log, H256, U256};
+use im::HashMap;
+use serde::Serialize;
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum DumpError {
+    #[snafu(display("Invalid JSON dump: {}", source))]
+    InvalidJson { source: serde_json::Error },
+    #[snafu(display("Invalid dump: {}", err))]
+    InvalidDump { err: String },
+    #[snafu(display("Invalid tree: {}", source))]
+    InvalidTree { source: Error },
+}
+
+pub type DumpResult<T> = std::result::Result<T, DumpError>;
+
+/// Tree whose logs are kept when a dump holds the logs of several trees
+#[derive(Clone, Debug, Default)]
+pub struct DumpFilter {
+    pub tree_address: Option<Address>,
+    pub identifier: Option<U256>,
+}
+
+impl DumpFilter {
+    fn matches(&self, log: &Log) -> bool {
+        self.tree_address
+            .map_or(true, |address| log.address == address)
+            && self.identifier.map_or(true, |identifier| {
+                log.topics.get(1) == Some(&topic(identifier))
+            })
+    }
+}
+
+/// Tree rebuilt from a dump, with the on-chain origin of each vertex when the
+/// dump has logs
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Dump {
+    #[serde(flatten)]
+    pub tree: Tree,
+    pub provenance: HashMap<u32, VertexProvenance>,
+}
+
+impl Dump {
+    /// Parent of each vertex in insertion order, the root being its own
+    /// parent, which parses back into the same tree
+    pub fn parents(&self) -> Vec<u32> {
+        (0..self.tree.size() as u32)
+            .map(|index| {
+                self.tree
+                    .get_vertex(index)
+                    .and_then(|vertex| vertex.get_parent())
+                    .unwrap_or(index)
+            })
+            .collect()
+    }
+
+    /// Draw the tree, one vertex per line. Branches are indented, while a
+    /// vertex with a single child is followed by it at the same indentation
+    /// so that long chains stay readable.
+    pub fn render(&self) -> String {
+        let size = self.tree.size() as u32;
+        let mut children = vec![vec![]; size as usize];
+        for index in 1..size {
+            if let Some(parent) = self
+                .tree
+                .get_vertex(index)
+                .and_then(|vertex| vertex.get_parent())
+            {
+                children[parent as usize].push(index);
+            }
+        }
+
+        let deepest = self.tree.get_deepest();
+        let mut out = String::new();
+        // vertex, prefix of its line, and prefix of its children lines
+        let mut stack = vec![];
+        if size > 0 {
+            stack.push((0, String::new(), String::new()));
+        }
+
+        while let Some((index, line_prefix, prefix)) = stack.pop() {
+            let depth = self.tree.get_vertex(index).unwrap().get_depth();
+            let _ = write!(out, "{}{} (depth {})", line_prefix, index, depth);
+            if deepest == Some(index) {
+                out.push_str(" deepest");
+            }
+            out.push('\n');
+
+            match children[index as usize].as_slice() {
+                [child] => stack.push((*child, prefix.clone(), prefix)),
+                branches => {
+                    for (i, child) in branches.iter().enumerate().rev() {
+                        let (connector, indent) = if i + 1 == branches.len() {
+                            ("└── ", "    ")
+                        } else {
+                            ("├── ", "│   ")
+                        };
+                        stack.push((
+                            *child,
+                            format!("{}{}", prefix, connector),
+                            format!("{}{}", prefix, indent),
+                        ));
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Rebuild the tree of `dump`, keeping only the logs of the tree selected by
+/// `filter`
+pub fn parse_dump(dump: &str, filter: &DumpFilter) -> DumpResult<Dump> {
+    let text = dump.trim_start();
+    if !(text.starts_with('[') || text.starts_with('{')) {
+        let parents = text
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                token.parse().map_err(|_| DumpError::InvalidDump {
+                    err: format!("`{}` is not a vertex index", token),
+                })
+            })
+            .collect::<DumpResult<Vec<u32>>>()?;
+        return from_parents(&parents);
+    }
+
+    let entries =
+        match serde_json::from_str::<Value>(text).context(InvalidJson)? {
+            Value::Array(entries) => entries,
+            Value::Object(mut response) => match response.remove("result") {
+                Some(Value::Array(entries)) => entries,
+                _ => return InvalidDump {
+                    err: "expected an `eth_getLogs` response with a `result` \
+                          array",
+                }
+                .fail(),
+            },
+            _ => unreachable!("JSON dumps start with an array or an object"),
+        };
+
+    if entries.iter().all(Value::is_number) {
+        let parents = entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .as_u64()
+                    .and_then(|parent| u32::try_from(parent).ok())
+                    .ok_or_else(|| DumpError::InvalidDump {
+                        err: format!("`{}` is not a vertex index", entry),
+                    })
+            })
+            .collect::<DumpResult<Vec<u32>>>()?;
+        return from_parents(&parents);
+    }
+
+    let logs: Vec<Log> =
+        serde_json::from_value(Value::Array(entries)).context(InvalidJson)?;
+    from_logs(logs, filter)
+}
+
+fn from_parents(parents: &[u32]) -> DumpResult<Dump> {
+    let mut tree = Tree::default();
+    for parent in parents {
+        tree = tree.insert_vertex(*parent).context(InvalidTree)?;
+    }
+
+    Ok(Dump {
+        tree,
+        provenance: HashMap::new(),
+    })
+}
+
+fn from_logs(logs: Vec<Log>, filter: &DumpFilter) -> DumpResult<Dump> {
+    let signature = VertexInsertedFilter::signature();
+    let mut logs: Vec<Log> = logs
+        .into_iter()
+        .filter(|log| log.removed != Some(true))
+        .filter(|log| log.topics.first() == Some(&signature))
+        .filter(|log| filter.matches(log))
+        .collect();
+
+    let trees: HashSet<_> = logs
+        .iter()
+        .map(|log| (log.address, log.topics.get(1).cloned()))
+        .collect();
+    if trees.len() > 1 {
+        return InvalidDump {
+            err: format!(
+                "logs of {} trees, select one by address and id",
+                trees.len()
+            ),
+        }
+        .fail();
+    }
+
+    logs.sort_by_key(|log| {
+        (
+            log.block_number.unwrap_or_default(),
+            log.log_index.unwrap_or_default(),
+        )
+    });
+
+    let mut dump = Dump::default();
+    for log in logs {
+        let event = decode_vertex_inserted(&log).context(InvalidTree)?;
+        dump.tree =
+            dump.tree.insert_vertex(event.parent).context(InvalidTree)?;
+
+        dump.provenance.insert(
+            dump.tree.get_last().unwrap(),
+            VertexProvenance {
+                block_number: log.block_number.unwrap_or_default(),
+                block_hash: log.block_hash.unwrap_or_default(),
+                tx_hash: log.transaction_hash.unwrap_or_default(),
+                log_index: log.log_index.unwrap_or_default(),
+                from: None,
+            },
+        );
+    }
+
+    Ok(dump)
+}
+
+fn topic(identifier: U256) -> H256 {
+    let mut topic = [0u8; 32];
+    identifier.to_big_endian(&mut topic);
+    H256::from(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_chain::vertex_inserted;
+
+    use ethers::types::U64;
+
+    fn log(tree: u64, block: u64, log_index: u64, parent: u32) -> Log {
+        Log {
+            block_number: Some(U64::from(block)),
+            log_index: Some(U256::from(log_index)),
+            ..vertex_inserted(
+                Address::from_low_u64_be(tree),
+                U256::zero(),
+                parent,
+            )
+        }
+    }
+
+    #[test]
+    fn test_parse_parents() {
+        let dump =
+            parse_dump("0 0\n1, 1\n3\n", &DumpFilter::default()).unwrap();
+        assert_eq!(dump.tree.size(), 5);
+        assert_eq!(dump.tree.get_deepest(), Some(4));
+        assert_eq!(dump.parents(), vec![0, 0, 1, 1, 3]);
+
+        let json = parse_dump("[0, 0, 1]", &DumpFilter::default()).unwrap();
+        assert_eq!(json.parents(), vec![0, 0, 1]);
+
+        assert!(matches!(
+            parse_dump("0 0 7", &DumpFilter::default()),
+            Err(DumpError::InvalidTree { .. })
+        ));
+        assert!(matches!(
+            parse_dump("0 -1", &DumpFilter::default()),
+            Err(DumpError::InvalidDump { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_logs() {
+        // out of order, with another tree's log
+        let logs = vec![log(1, 5, 1, 1), log(1, 3, 0, 0), log(1, 5, 0, 0)];
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": logs,
+        });
+
+        let dump =
+            parse_dump(&response.to_string(), &DumpFilter::default()).unwrap();
+        assert_eq!(dump.parents(), vec![0, 0, 1]);
+        assert_eq!(dump.provenance[&2].log_index, U256::one());
+
+        let mut mixed = logs.clone();
+        mixed.push(log(2, 4, 0, 0));
+        let text = serde_json::to_string(&mixed).unwrap();
+        assert!(matches!(
+            parse_dump(&text, &DumpFilter::default()),
+            Err(DumpError::InvalidDump { .. })
+        ));
+
+        let filter = DumpFilter {
+            tree_address: Some(Address::from_low_u64_be(2)),
+            identifier: Some(U256::zero()),
+        };
+        assert_eq!(parse_dump(&text, &filter).unwrap().tree.size(), 1);
+    }
+
+    #[test]
+    fn test_render() {
+        let dump = parse_dump("0 0 1 1 3 0", &DumpFilter::default()).unwrap();
+        assert_eq!(
+            dump.render(),
+            "0 (depth 0)\n\
+             ├── 1 (depth 1)\n\
+             │   ├── 2 (depth 2)\n\
+             │   └── 3 (depth 2)\n\
+             │       4 (depth 3) deepest\n\
+             └── 5 (depth 1)\n"
+        );
+    }
+}
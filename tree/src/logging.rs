@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tonic::Status;
+use tracing::{debug, field, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Output format of the server logs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown format `{}`, expected `text` or `json`",
+                format
+            )),
+        }
+    }
+}
+
+/// Install the process-wide subscriber, keeping the events enabled by
+/// `filter`, an `EnvFilter` directive such as `info,tree=debug`
+pub fn init_logging(format: LogFormat, filter: &str) {
+    let builder =
+        tracing_subscriber::fmt().with_env_filter(EnvFilter::new(filter));
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Run `request` in a span with a new request id, logging whether it failed.
+/// The `tree` and `block_number` fields are recorded once the state of the
+/// requested tree is known.
+pub async fn in_request_span<T, F>(
+    method: &'static str,
+    request: F,
+) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    let span = info_span!(
+        "request",
+        id = REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        method,
+        tree = field::Empty,
+        block_number = field::Empty,
+    );
+
+    async move {
+        let result = request.await;
+
+        match &result {
+            Ok(_) => debug!("Request answered"),
+            Err(status) => warn!(
+                code = ?status.code(),
+                "Request failed: {}",
+                status.message()
+            ),
+        }
+
+        result
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
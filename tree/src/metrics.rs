@@ -0,0 +1,352 @@
+use crate::fold::tree_delegate::{TreeKey, TreeState};
+
+use ethers::types::Address;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Server, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tonic::Code;
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("tree_requests_total", "Requests by method and code"),
+            &["method", "code"],
+        )
+        .unwrap(),
+    )
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "tree_request_duration_seconds",
+                "Time to answer requests, by method",
+            ),
+            &["method"],
+        )
+        .unwrap(),
+    )
+});
+
+static COMPUTE_STATE_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "tree_compute_state_duration_seconds",
+            "Time to compute a tree state from its logs",
+        ))
+        .unwrap(),
+    )
+});
+
+static RPC_CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("tree_rpc_calls_total", "Ethereum node calls by method"),
+            &["method"],
+        )
+        .unwrap(),
+    )
+});
+
+static RPC_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "tree_rpc_retries_total",
+                "Ethereum node calls retried after a transient failure",
+            ),
+            &["method"],
+        )
+        .unwrap(),
+    )
+});
+
+static RPC_ENDPOINT_UP: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "tree_rpc_endpoint_up",
+                "Whether each Ethereum node endpoint is healthy",
+            ),
+            &["endpoint"],
+        )
+        .unwrap(),
+    )
+});
+
+static LOGS_FETCHED: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "tree_logs_fetched_total",
+            "`VertexInserted` logs fetched from the node",
+        )
+        .unwrap(),
+    )
+});
+
+static BLOOM_SKIPS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "tree_bloom_skips_total",
+            "Blocks folded without querying logs thanks to their bloom filter",
+        )
+        .unwrap(),
+    )
+});
+
+static TREE_VERTICES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("tree_vertices", "Vertices in the latest tree state"),
+            &["tree", "identifier", "from_block"],
+        )
+        .unwrap(),
+    )
+});
+
+static TREE_DEEPEST_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "tree_deepest_depth",
+                "Depth of the deepest vertex in the latest tree state",
+            ),
+            &["tree", "identifier", "from_block"],
+        )
+        .unwrap(),
+    )
+});
+
+static TREE_DIVERGENCES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "tree_divergences_total",
+                "Mismatches found by verification between trees and their \
+                 contract, by contract",
+            ),
+            &["tree"],
+        )
+        .unwrap(),
+    )
+});
+
+fn register<C: prometheus::core::Collector + Clone + 'static>(
+    collector: C,
+) -> C {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+/// Record a request to `method` answered with `code`
+pub fn observe_request(method: &str, code: Code, duration: Duration) {
+    REQUESTS
+        .with_label_values(&[method, &format!("{:?}", code)])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[method])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_compute_state(duration: Duration) {
+    COMPUTE_STATE_DURATION.observe(duration.as_secs_f64());
+}
+
+pub fn inc_rpc_calls(method: &str) {
+    RPC_CALLS.with_label_values(&[method]).inc();
+}
+
+pub fn inc_rpc_retries(method: &str) {
+    RPC_RETRIES.with_label_values(&[method]).inc();
+}
+
+pub fn set_rpc_endpoint_up(endpoint: &str, up: bool) {
+    RPC_ENDPOINT_UP
+        .with_label_values(&[endpoint])
+        .set(up as i64);
+}
+
+pub fn inc_logs_fetched(count: usize) {
+    LOGS_FETCHED.inc_by(count as u64);
+}
+
+pub fn inc_bloom_skips() {
+    BLOOM_SKIPS.inc();
+}
+
+/// Labels telling apart the trees of `key`: the same contract and
+/// identifier read from another block are another tree
+fn tree_labels(key: &TreeKey) -> [String; 3] {
+    [
+        format!("{:?}", key.tree_address),
+        key.identifier.to_string(),
+        key.from_block.to_string(),
+    ]
+}
+
+/// Record size and deepest depth of the latest state of a folded tree
+pub fn observe_tree(key: &TreeKey, state: &TreeState) {
+    let labels = tree_labels(key);
+    let labels = [labels[0].as_str(), labels[1].as_str(), labels[2].as_str()];
+
+    let (size, depth) = state
+        .tree
+        .as_ref()
+        .map(|tree| {
+            let depth = tree
+                .get_deepest()
+                .and_then(|index| tree.get_vertex(index))
+                .map(|vertex| vertex.get_depth())
+                .unwrap_or(0);
+            (tree.size(), depth)
+        })
+        .unwrap_or((0, 0));
+
+    TREE_VERTICES.with_label_values(&labels).set(size as i64);
+    TREE_DEEPEST_DEPTH
+        .with_label_values(&labels)
+        .set(depth as i64);
+}
+
+/// Stop exporting the size and deepest depth of a tree no longer folded
+pub fn forget_tree(key: &TreeKey) {
+    let labels = tree_labels(key);
+    let labels = [labels[0].as_str(), labels[1].as_str(), labels[2].as_str()];
+
+    // the tree may have never been observed
+    let _ = TREE_VERTICES.remove_label_values(&labels);
+    let _ = TREE_DEEPEST_DEPTH.remove_label_values(&labels);
+}
+
+pub fn inc_divergences(tree: &Address) {
+    TREE_DIVERGENCES
+        .with_label_values(&[&format!("{:?}", tree)])
+        .inc();
+}
+
+/// All metrics in the Prometheus text format
+pub fn encode() -> String {
+    // register every metric, so they are exported before their first update
+    Lazy::force(&REQUESTS);
+    Lazy::force(&REQUEST_DURATION);
+    Lazy::force(&COMPUTE_STATE_DURATION);
+    Lazy::force(&RPC_CALLS);
+    Lazy::force(&RPC_RETRIES);
+    Lazy::force(&RPC_ENDPOINT_UP);
+    Lazy::force(&LOGS_FETCHED);
+    Lazy::force(&BLOOM_SKIPS);
+    Lazy::force(&TREE_VERTICES);
+    Lazy::force(&TREE_DEEPEST_DEPTH);
+    Lazy::force(&TREE_DIVERGENCES);
+
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Serve the metrics at `GET /metrics` on `address` until `shutdown`
+/// completes
+pub async fn serve_metrics(
+    address: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(
+            |request: hyper::Request<Body>| async move {
+                let response = if request.method() == Method::GET
+                    && request.uri().path() == "/metrics"
+                {
+                    hyper::Response::builder()
+                        .header(
+                            header::CONTENT_TYPE,
+                            TextEncoder::new().format_type(),
+                        )
+                        .body(Body::from(encode()))
+                } else {
+                    hyper::Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                };
+
+                Ok::<_, Infallible>(response.unwrap())
+            },
+        ))
+    });
+
+    Server::try_bind(&address)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree_lib::Tree;
+
+    use ethers::types::{H256, U256, U64};
+
+    #[test]
+    fn test_encode() {
+        observe_request("GetState", Code::NotFound, Duration::from_millis(5));
+        inc_logs_fetched(3);
+
+        let tree = Tree::default()
+            .insert_vertex(0)
+            .unwrap()
+            .insert_vertex(0)
+            .unwrap()
+            .insert_vertex(1)
+            .unwrap();
+        let key = TreeKey {
+            identifier: U256::from(7),
+            from_block: U64::from(5),
+            ..Default::default()
+        };
+        observe_tree(
+            &key,
+            &TreeState {
+                caller_address: Address::zero(),
+                identifier: U256::from(7),
+                tree: Some(tree),
+                provenance: im::HashMap::new(),
+                block_number: U64::from(1),
+                block_hash: H256::zero(),
+                config: Default::default(),
+            },
+        );
+
+        let metrics = encode();
+        assert!(metrics.contains(
+            "tree_requests_total{code=\"NotFound\",method=\"GetState\"} 1"
+        ));
+        assert!(metrics.contains("tree_request_duration_seconds_bucket"));
+        assert!(metrics.contains("tree_bloom_skips_total 0"));
+        assert!(metrics.contains(&format!(
+            "tree_vertices{{from_block=\"5\",identifier=\"7\",\
+             tree=\"{:?}\"}} 3",
+            Address::zero()
+        )));
+        assert!(metrics.contains(&format!(
+            "tree_deepest_depth{{from_block=\"5\",identifier=\"7\",\
+             tree=\"{:?}\"}} 2",
+            Address::zero()
+        )));
+
+        forget_tree(&key);
+        assert!(!encode().contains("identifier=\"7\""));
+    }
+}
@@ -0,0 +1,514 @@
+//! In-memory chain implementing the `Middleware` methods the tree delegate
+//! uses, to drive `sync`, `fold` and the servers without an Ethereum node.
+//!
+//! Blocks are mined with the logs given to them, get a logs bloom of their
+//! logs' addresses and topics, and stay queryable by hash after a reorg drops
+//! them from the canonical chain. Each log is emitted by a transaction of its
+//! own, sent by the sender its block was mined with. Contract storage is set
+//! slot by slot and is the same at every block, as are the results of
+//! contract calls, set call by call. Like hosted providers, the chain can
+//! reject log queries spanning too many blocks, and it can be made
+//! unreachable.
+
+use crate::fold::contracts::tree_contract::VertexInsertedFilter;
+
+use async_trait::async_trait;
+use ethers::abi::{self, Token};
+use ethers::contract::EthEvent;
+use ethers::providers::{
+    FromErr, Middleware, MockProvider, Provider, ProviderError,
+};
+use ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, Block, BlockId,
+    BlockNumber, Bloom, Bytes, Filter, FilterBlockOption, Log, NameOrAddress,
+    Transaction, TxHash, ValueOrArray, H256, U256, U64,
+};
+use ethers::utils::keccak256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Chain id of every mock chain
+pub const CHAIN_ID: u64 = 31337;
+
+#[derive(Debug)]
+pub struct MockChainError(pub String);
+
+impl std::fmt::Display for MockChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for MockChainError {}
+
+impl FromErr<ProviderError> for MockChainError {
+    fn from(src: ProviderError) -> Self {
+        MockChainError(src.to_string())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Chain {
+    // hashes of the canonical blocks, indexed by block number
+    canonical: Vec<H256>,
+    // every block mined, canonical or not
+    blocks: HashMap<H256, Block<H256>>,
+    logs: HashMap<H256, Vec<Log>>,
+    // transaction of every log mined, by hash
+    transactions: HashMap<H256, Transaction>,
+    // contract storage, the same at every block
+    storage: HashMap<(Address, H256), H256>,
+    // results of contract calls by contract and calldata
+    calls: HashMap<(Address, Vec<u8>), Vec<u8>>,
+    // blocks mined so far, making every hash unique
+    mined: u64,
+    // widest block range of a log query, if limited
+    max_log_range: Option<u64>,
+    // log queries answered or rejected so far
+    log_queries: usize,
+    // whether every call fails as if the node was down
+    unreachable: bool,
+}
+
+impl Chain {
+    fn mine(&mut self, from: Address, logs: Vec<Log>) -> Block<H256> {
+        self.mined += 1;
+        let number = U64::from(self.canonical.len());
+        let parent_hash = self.canonical.last().cloned().unwrap_or_default();
+        let hash = H256::from(keccak256(
+            [parent_hash.as_bytes(), &self.mined.to_be_bytes()].concat(),
+        ));
+
+        let mut bloom = Bloom::zero();
+        let logs: Vec<Log> = logs
+            .into_iter()
+            .enumerate()
+            .map(|(index, log)| {
+                accrue(&mut bloom, log.address.as_bytes());
+                for topic in &log.topics {
+                    accrue(&mut bloom, topic.as_bytes());
+                }
+
+                Log {
+                    block_hash: Some(hash),
+                    block_number: Some(number),
+                    transaction_hash: Some(H256::from(keccak256(
+                        [hash.as_bytes(), &index.to_be_bytes()].concat(),
+                    ))),
+                    transaction_index: Some(U64::from(index)),
+                    log_index: Some(U256::from(index)),
+                    removed: Some(false),
+                    ..log
+                }
+            })
+            .collect();
+
+        let block = Block {
+            hash: Some(hash),
+            parent_hash,
+            number: Some(number),
+            timestamp: U256::from(number.as_u64()),
+            logs_bloom: Some(bloom),
+            ..Default::default()
+        };
+
+        for log in &logs {
+            let tx_hash = log.transaction_hash.unwrap();
+            self.transactions.insert(
+                tx_hash,
+                Transaction {
+                    hash: tx_hash,
+                    from,
+                    to: Some(log.address),
+                    block_hash: log.block_hash,
+                    block_number: log.block_number,
+                    transaction_index: log.transaction_index,
+                    ..Default::default()
+                },
+            );
+        }
+
+        self.canonical.push(hash);
+        self.blocks.insert(hash, block.clone());
+        self.logs.insert(hash, logs);
+        block
+    }
+
+    fn head(&self) -> U64 {
+        U64::from(self.canonical.len() - 1)
+    }
+
+    fn number(&self, block: &BlockNumber) -> U64 {
+        match block {
+            BlockNumber::Number(number) => *number,
+            BlockNumber::Earliest => U64::zero(),
+            BlockNumber::Latest | BlockNumber::Pending => self.head(),
+        }
+    }
+
+    fn canonical_hash(&self, number: U64) -> Option<H256> {
+        self.canonical.get(number.as_usize()).cloned()
+    }
+
+    fn ensure_reachable(&self) -> Result<(), MockChainError> {
+        if self.unreachable {
+            return Err(MockChainError("connection refused".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Set the bloom bits of `input`, as Ethereum does for each log address and
+/// topic
+fn accrue(bloom: &mut Bloom, input: &[u8]) {
+    let hash = keccak256(input);
+    let bytes = bloom.as_bytes_mut();
+
+    for i in [0, 2, 4] {
+        let bit = ((hash[i] as usize) << 8 | hash[i + 1] as usize) & 2047;
+        bytes[255 - bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+fn value_matches<T: PartialEq>(
+    filter: &Option<ValueOrArray<T>>,
+    value: &T,
+) -> bool {
+    match filter {
+        None => true,
+        Some(ValueOrArray::Value(expected)) => expected == value,
+        Some(ValueOrArray::Array(expected)) => expected.contains(value),
+    }
+}
+
+fn matches_filter(filter: &Filter, log: &Log) -> bool {
+    value_matches(&filter.address, &log.address)
+        && filter.topics.iter().enumerate().all(|(i, topic)| {
+            topic.is_none()
+                || log
+                    .topics
+                    .get(i)
+                    .map_or(false, |value| value_matches(topic, value))
+        })
+}
+
+/// In-memory chain of id `CHAIN_ID` starting at an empty genesis block
+#[derive(Debug)]
+pub struct MockChain {
+    inner: Provider<MockProvider>,
+    chain: Mutex<Chain>,
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        let mut chain = Chain::default();
+        chain.mine(Address::zero(), vec![]);
+
+        MockChain {
+            inner: Provider::new(MockProvider::new()),
+            chain: Mutex::new(chain),
+        }
+    }
+
+    /// Mine a block on top of the head with `logs`, returning it
+    pub fn mine(&self, logs: Vec<Log>) -> Block<H256> {
+        self.mine_from(Address::zero(), logs)
+    }
+
+    /// Mine a block on top of the head with `logs`, each emitted by a
+    /// transaction sent by `from`, returning it
+    pub fn mine_from(&self, from: Address, logs: Vec<Log>) -> Block<H256> {
+        self.chain.lock().unwrap().mine(from, logs)
+    }
+
+    /// Mine `count` blocks without logs, returning the last one
+    pub fn mine_empty(&self, count: usize) -> Block<H256> {
+        let mut chain = self.chain.lock().unwrap();
+        let mut block = None;
+        for _ in 0..count {
+            block = Some(chain.mine(Address::zero(), vec![]));
+        }
+        block.expect("should mine at least one block")
+    }
+
+    /// Drop the last `depth` blocks from the canonical chain and mine a block
+    /// with the logs of each of `blocks` in their place, returning the new
+    /// head. The dropped blocks can still be queried by hash.
+    pub fn reorg(&self, depth: usize, blocks: Vec<Vec<Log>>) -> Block<H256> {
+        let mut chain = self.chain.lock().unwrap();
+        assert!(
+            depth < chain.canonical.len(),
+            "cannot reorg the genesis block"
+        );
+
+        let fork = chain.canonical.len() - depth;
+        chain.canonical.truncate(fork);
+        for logs in blocks {
+            chain.mine(Address::zero(), logs);
+        }
+
+        let head = chain.head();
+        let hash = chain.canonical_hash(head).unwrap();
+        chain.blocks[&hash].clone()
+    }
+
+    /// Set storage slot `slot` of contract `address` to `value`
+    pub fn set_storage(&self, address: Address, slot: H256, value: H256) {
+        self.chain
+            .lock()
+            .unwrap()
+            .storage
+            .insert((address, slot), value);
+    }
+
+    /// Answer calls to contract `address` with `data` by `output`, calls
+    /// without a result being reverted
+    pub fn set_call(&self, address: Address, data: Bytes, output: Bytes) {
+        self.chain.lock().unwrap().calls.insert(
+            (address, data.as_ref().to_vec()),
+            output.as_ref().to_vec(),
+        );
+    }
+
+    /// Reject log queries over more than `max_range` blocks, as providers
+    /// limiting their results do
+    pub fn limit_log_range(&self, max_range: u64) {
+        self.chain.lock().unwrap().max_log_range = Some(max_range);
+    }
+
+    /// Number of log queries made so far, rejected ones included
+    pub fn log_queries(&self) -> usize {
+        self.chain.lock().unwrap().log_queries
+    }
+
+    /// Fail every call as if the node was down, or stop doing so
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.chain.lock().unwrap().unreachable = unreachable;
+    }
+
+    /// Latest canonical block
+    pub fn head(&self) -> Block<H256> {
+        let chain = self.chain.lock().unwrap();
+        let hash = chain.canonical_hash(chain.head()).unwrap();
+        chain.blocks[&hash].clone()
+    }
+}
+
+/// `VertexInserted` log of the tree of `identifier` at `tree_address`,
+/// inserting a vertex under `parent`
+pub fn vertex_inserted(
+    tree_address: Address,
+    identifier: U256,
+    parent: u32,
+) -> Log {
+    let mut id = [0u8; 32];
+    identifier.to_big_endian(&mut id);
+
+    Log {
+        address: tree_address,
+        topics: vec![VertexInsertedFilter::signature(), H256::from(id)],
+        data: Bytes::from(abi::encode(&[Token::Uint(U256::from(parent))])),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl Middleware for MockChain {
+    type Error = MockChainError;
+    type Provider = MockProvider;
+    type Inner = Provider<MockProvider>;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    async fn get_chainid(&self) -> Result<U256, Self::Error> {
+        self.chain.lock().unwrap().ensure_reachable()?;
+        Ok(U256::from(CHAIN_ID))
+    }
+
+    async fn get_block_number(&self) -> Result<U64, Self::Error> {
+        let chain = self.chain.lock().unwrap();
+        chain.ensure_reachable()?;
+        Ok(chain.head())
+    }
+
+    async fn get_block<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<Option<Block<H256>>, Self::Error> {
+        let chain = self.chain.lock().unwrap();
+        chain.ensure_reachable()?;
+        let hash = match block_hash_or_number.into() {
+            BlockId::Hash(hash) => Some(hash),
+            BlockId::Number(number) => {
+                chain.canonical_hash(chain.number(&number))
+            }
+        };
+
+        Ok(hash.and_then(|hash| chain.blocks.get(&hash).cloned()))
+    }
+
+    async fn get_transaction<T: Send + Sync + Into<TxHash>>(
+        &self,
+        transaction_hash: T,
+    ) -> Result<Option<Transaction>, Self::Error> {
+        let chain = self.chain.lock().unwrap();
+        chain.ensure_reachable()?;
+        Ok(chain.transactions.get(&transaction_hash.into()).cloned())
+    }
+
+    async fn get_storage_at<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        location: H256,
+        _block: Option<BlockId>,
+    ) -> Result<H256, Self::Error> {
+        let address = match from.into() {
+            NameOrAddress::Address(address) => address,
+            NameOrAddress::Name(name) => {
+                return Err(MockChainError(format!("unknown name {}", name)))
+            }
+        };
+
+        let chain = self.chain.lock().unwrap();
+        chain.ensure_reachable()?;
+        Ok(chain
+            .storage
+            .get(&(address, location))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        _block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        let chain = self.chain.lock().unwrap();
+        chain.ensure_reachable()?;
+
+        let address = match tx.to() {
+            Some(NameOrAddress::Address(address)) => *address,
+            to => return Err(MockChainError(format!("cannot call {:?}", to))),
+        };
+        let data = tx
+            .data()
+            .map(|data| data.as_ref().to_vec())
+            .unwrap_or_default();
+
+        chain
+            .calls
+            .get(&(address, data))
+            .map(|output| Bytes::from(output.clone()))
+            .ok_or_else(|| MockChainError("execution reverted".to_string()))
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, Self::Error> {
+        let mut chain = self.chain.lock().unwrap();
+        chain.ensure_reachable()?;
+        chain.log_queries += 1;
+
+        let hashes = match &filter.block_option {
+            FilterBlockOption::AtBlockHash(hash) => {
+                if !chain.blocks.contains_key(hash) {
+                    return Err(MockChainError(format!(
+                        "unknown block {:?}",
+                        hash
+                    )));
+                }
+                vec![*hash]
+            }
+            FilterBlockOption::Range {
+                from_block,
+                to_block,
+            } => {
+                let from = from_block
+                    .as_ref()
+                    .map_or(U64::zero(), |block| chain.number(block));
+                let to = to_block
+                    .as_ref()
+                    .map_or(chain.head(), |block| chain.number(block));
+
+                if let Some(max_range) = chain.max_log_range {
+                    if to.as_u64() + 1 > from.as_u64() + max_range {
+                        return Err(MockChainError(format!(
+                            "query returned more than {} results",
+                            max_range
+                        )));
+                    }
+                }
+
+                (from.as_u64()..=to.as_u64())
+                    .filter_map(|n| chain.canonical_hash(U64::from(n)))
+                    .collect()
+            }
+        };
+
+        Ok(hashes
+            .iter()
+            .flat_map(|hash| chain.logs[hash].iter())
+            .filter(|log| matches_filter(filter, log))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use state_fold::utils as fold_utils;
+
+    #[tokio::test]
+    async fn test_bloom_and_logs() {
+        let chain = MockChain::new();
+        let tree = Address::from_low_u64_be(1);
+        let other = Address::from_low_u64_be(2);
+
+        let block = chain.mine(vec![
+            vertex_inserted(tree, U256::zero(), 0),
+            vertex_inserted(other, U256::one(), 0),
+        ]);
+        let bloom = block.logs_bloom.unwrap();
+        assert!(fold_utils::contains_address(&bloom, &tree));
+        assert!(fold_utils::contains_topic(&bloom, &U256::one()));
+        assert!(!fold_utils::contains_address(
+            &bloom,
+            &Address::from_low_u64_be(3)
+        ));
+
+        let filter = Filter::new().address(tree).from_block(0).to_block(1);
+        let logs = chain.get_logs(&filter).await.unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].block_hash, block.hash);
+    }
+
+    #[tokio::test]
+    async fn test_reorg() {
+        let chain = MockChain::new();
+        let tree = Address::from_low_u64_be(1);
+
+        let dropped = chain.mine(vec![vertex_inserted(tree, U256::zero(), 0)]);
+        let head = chain.reorg(1, vec![vec![], vec![]]);
+
+        assert_eq!(head.number, Some(U64::from(2)));
+        assert_eq!(chain.get_block_number().await.unwrap(), U64::from(2));
+        assert_ne!(
+            chain.get_block(1u64).await.unwrap().unwrap().hash,
+            dropped.hash
+        );
+
+        // dropped blocks are still known by hash, but not in ranges
+        let at_hash = Filter::new().at_block_hash(dropped.hash.unwrap());
+        assert_eq!(chain.get_logs(&at_hash).await.unwrap().len(), 1);
+        let range = Filter::new().from_block(0).to_block(2);
+        assert!(chain.get_logs(&range).await.unwrap().is_empty());
+    }
+}
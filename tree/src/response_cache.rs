@@ -0,0 +1,228 @@
+use crate::fold::tree_delegate::TreeKey;
+
+use ethers::types::H256;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Tree initial state, the hash of the block a response was computed at and
+/// the initial state version setting the response format
+pub type CacheKey = (TreeKey, H256, u64);
+
+/// Bounded cache of serialized tree responses. Concurrent requests for the
+/// same entry wait for a single computation, and the least recently used
+/// entries are evicted once the responses held exceed `max_bytes`. A
+/// `max_bytes` of zero disables the cache.
+#[derive(Debug)]
+pub struct ResponseCache {
+    max_bytes: usize,
+    inner: Mutex<CacheEntries>,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    entries: HashMap<CacheKey, Entry>,
+    // ready entries by last use
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    cell: Arc<OnceCell<Arc<String>>>,
+    // unset while the response is being computed
+    last_used: Option<u64>,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize) -> Self {
+        ResponseCache {
+            max_bytes,
+            inner: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Bytes of responses held
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+
+    /// Get the response cached for `key`, computing it with `compute` if
+    /// no other request is already doing so. Failed computations are not
+    /// cached.
+    pub async fn get_or_try_insert_with<F, Fut, E>(
+        &self,
+        key: CacheKey,
+        compute: F,
+    ) -> Result<Arc<String>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, E>>,
+    {
+        if self.max_bytes == 0 {
+            return compute().await.map(Arc::new);
+        }
+
+        let cell = self.cell(key);
+        let result = cell
+            .get_or_try_init(|| async move { compute().await.map(Arc::new) })
+            .await
+            .map(Arc::clone);
+
+        match &result {
+            Ok(response) => self.touch(key, &cell, response.len()),
+            Err(_) => self.forget(key, &cell),
+        }
+
+        result
+    }
+
+    fn cell(&self, key: CacheKey) -> Arc<OnceCell<Arc<String>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.entry(key).or_insert_with(|| Entry {
+            cell: Arc::new(OnceCell::new()),
+            last_used: None,
+        });
+
+        Arc::clone(&entry.cell)
+    }
+
+    /// Mark the entry of `key` as just used, accounting for it when it was
+    /// just computed, then evict entries over the limit
+    fn touch(
+        &self,
+        key: CacheKey,
+        cell: &Arc<OnceCell<Arc<String>>>,
+        size: usize,
+    ) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        let entry = match inner.entries.get_mut(&key) {
+            Some(entry) if Arc::ptr_eq(&entry.cell, cell) => entry,
+            // evicted while being answered
+            _ => return,
+        };
+
+        match entry.last_used.take() {
+            Some(tick) => {
+                inner.recency.remove(&tick);
+            }
+            None => inner.bytes += size,
+        }
+
+        inner.tick += 1;
+        entry.last_used = Some(inner.tick);
+        inner.recency.insert(inner.tick, key);
+
+        while inner.bytes > self.max_bytes {
+            let (tick, evicted) = match inner.recency.iter().next() {
+                Some((tick, evicted)) => (*tick, *evicted),
+                None => break,
+            };
+
+            inner.recency.remove(&tick);
+            if let Some(entry) = inner.entries.remove(&evicted) {
+                inner.bytes -= entry.cell.get().map_or(0, |r| r.len());
+            }
+        }
+    }
+
+    /// Drop the entry of `key` after its computation failed, so the next
+    /// request retries it
+    fn forget(&self, key: CacheKey, cell: &Arc<OnceCell<Arc<String>>>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let failed = match inner.entries.get(&key) {
+            Some(entry) => {
+                Arc::ptr_eq(&entry.cell, cell) && entry.last_used.is_none()
+            }
+            None => false,
+        };
+
+        if failed && !cell.initialized() {
+            inner.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn key(block: u64) -> CacheKey {
+        (TreeKey::default(), H256::from_low_u64_be(block), 1)
+    }
+
+    async fn get(
+        cache: &ResponseCache,
+        block: u64,
+        computed: &AtomicUsize,
+    ) -> Arc<String> {
+        cache
+            .get_or_try_insert_with(key(block), || async {
+                computed.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok::<_, ()>(format!("tree{}", block))
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let cache = ResponseCache::new(1024);
+        let computed = AtomicUsize::new(0);
+
+        let (first, second) =
+            tokio::join!(get(&cache, 1, &computed), get(&cache, 1, &computed));
+        assert_eq!(first, second);
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+
+        get(&cache, 1, &computed).await;
+        assert_eq!(computed.load(Ordering::SeqCst), 1, "Response is cached");
+        assert_eq!(cache.bytes(), "tree1".len());
+    }
+
+    #[tokio::test]
+    async fn test_evict_least_recently_used() {
+        // room for two responses
+        let cache = ResponseCache::new(10);
+        let computed = AtomicUsize::new(0);
+
+        get(&cache, 1, &computed).await;
+        get(&cache, 2, &computed).await;
+        get(&cache, 1, &computed).await;
+        get(&cache, 3, &computed).await;
+        assert_eq!(computed.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.bytes(), 10);
+
+        get(&cache, 1, &computed).await;
+        assert_eq!(computed.load(Ordering::SeqCst), 3, "1 was used recently");
+        get(&cache, 2, &computed).await;
+        assert_eq!(computed.load(Ordering::SeqCst), 4, "2 was evicted");
+    }
+
+    #[tokio::test]
+    async fn test_errors_not_cached() {
+        let cache = ResponseCache::new(1024);
+
+        let failed = cache
+            .get_or_try_insert_with(key(1), || async { Err("unavailable") })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(cache.bytes(), 0);
+
+        let retried = cache
+            .get_or_try_insert_with(key(1), || async {
+                Ok::<_, &str>("tree1".to_string())
+            })
+            .await;
+        assert_eq!(retried.unwrap().as_str(), "tree1");
+    }
+}
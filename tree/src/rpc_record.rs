@@ -0,0 +1,275 @@
+//! Recording of the JSON-RPC calls made to the Ethereum node, one call per
+//! line of an NDJSON file, and their replay without a node.
+//!
+//! A recording of a run against a real chain can be replayed by a
+//! `Provider<ReplayClient>`, turning the run into a deterministic test. Each
+//! line holds the `method`, the `params` and either the `result` or the
+//! `error` message of a call.
+
+use async_trait::async_trait;
+use ethers::providers::{JsonRpcClient, ProviderError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub")]
+pub enum RecordError {
+    #[snafu(display("{}", source))]
+    Client {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[snafu(display("Invalid JSON-RPC value: {}", source))]
+    Json { source: serde_json::Error },
+    #[snafu(display("Could not write recording: {}", source))]
+    WriteRecording { source: std::io::Error },
+    #[snafu(display("Could not read recording {}: {}", path.display(), source))]
+    ReadRecording {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid recording line {}: {}", line, source))]
+    ParseRecording {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[snafu(display("No recorded `{}` call with params {}", method, params))]
+    NotRecorded { method: String, params: String },
+    #[snafu(display("Recorded `{}` call failed: {}", method, err))]
+    RecordedFailure { method: String, err: String },
+}
+
+impl From<RecordError> for ProviderError {
+    fn from(src: RecordError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(src))
+    }
+}
+
+/// JSON-RPC call as stored in a recording
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedCall {
+    pub method: String,
+    pub params: Value,
+    #[serde(default)]
+    pub result: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Client recording every call made through `inner`, and its response, to a
+/// file
+#[derive(Debug)]
+pub struct RecordingClient<P> {
+    inner: P,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl<P: JsonRpcClient> RecordingClient<P> {
+    /// Record the calls made through `inner` to `path`, truncating it
+    pub fn create(inner: P, path: &Path) -> std::io::Result<Self> {
+        Ok(RecordingClient {
+            inner,
+            file: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    fn write(&self, call: &RecordedCall) -> Result<(), RecordError> {
+        let line = serde_json::to_string(call).context(Json)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)
+            .and_then(|_| file.flush())
+            .context(WriteRecording)
+    }
+}
+
+#[async_trait]
+impl<P> JsonRpcClient for RecordingClient<P>
+where
+    P: JsonRpcClient,
+    P::Error: Send + Sync + 'static,
+{
+    type Error = RecordError;
+
+    async fn request<T, R>(
+        &self,
+        method: &str,
+        params: T,
+    ) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let recorded_params = serde_json::to_value(&params).context(Json)?;
+        let response = self.inner.request::<T, Value>(method, params).await;
+
+        self.write(&RecordedCall {
+            method: method.to_string(),
+            params: recorded_params,
+            result: response.as_ref().ok().cloned().unwrap_or(Value::Null),
+            error: response.as_ref().err().map(|e| e.to_string()),
+        })?;
+
+        let result = response.map_err(|e| RecordError::Client {
+            source: Box::new(e),
+        })?;
+        serde_json::from_value(result).context(Json)
+    }
+}
+
+/// Client answering calls from a recording, without a node. Calls are matched
+/// by method and params, and repeated calls are answered in the order they
+/// were recorded, the last answer being repeated once the others are used.
+#[derive(Debug)]
+pub struct ReplayClient {
+    calls: Mutex<HashMap<(String, String), VecDeque<RecordedCall>>>,
+}
+
+impl ReplayClient {
+    /// Replay the recording at `path`
+    pub fn open(path: &Path) -> Result<Self, RecordError> {
+        let file = File::open(path).context(ReadRecording { path })?;
+
+        let mut calls = vec![];
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context(ReadRecording { path })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            calls.push(
+                serde_json::from_str(&line)
+                    .context(ParseRecording { line: index + 1 })?,
+            );
+        }
+
+        Ok(Self::new(calls))
+    }
+
+    /// Replay `calls`, in the order they were made
+    pub fn new(calls: Vec<RecordedCall>) -> Self {
+        let mut by_request: HashMap<_, VecDeque<_>> = HashMap::new();
+        for call in calls {
+            by_request
+                .entry((call.method.clone(), call.params.to_string()))
+                .or_default()
+                .push_back(call);
+        }
+
+        ReplayClient {
+            calls: Mutex::new(by_request),
+        }
+    }
+
+    fn next(&self, method: &str, params: &Value) -> Option<RecordedCall> {
+        let mut calls = self.calls.lock().unwrap();
+        let recorded =
+            calls.get_mut(&(method.to_string(), params.to_string()))?;
+
+        if recorded.len() > 1 {
+            recorded.pop_front()
+        } else {
+            recorded.front().cloned()
+        }
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for ReplayClient {
+    type Error = RecordError;
+
+    async fn request<T, R>(
+        &self,
+        method: &str,
+        params: T,
+    ) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(&params).context(Json)?;
+        let call = self.next(method, &params).ok_or_else(|| {
+            RecordError::NotRecorded {
+                method: method.to_string(),
+                params: params.to_string(),
+            }
+        })?;
+
+        match call.error {
+            Some(err) => RecordedFailure { method, err }.fail(),
+            None => serde_json::from_value(call.result).context(Json),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ethers::providers::MockProvider;
+    use ethers::types::U64;
+
+    fn recording_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tree-{}-{}.ndjson",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = recording_path("record");
+        let mock = MockProvider::new();
+        mock.push(U64::from(7)).unwrap();
+        mock.push(U64::from(8)).unwrap();
+
+        let recorder = RecordingClient::create(mock, &path).unwrap();
+        let first: U64 = recorder.request("eth_blockNumber", ()).await.unwrap();
+        let second: U64 =
+            recorder.request("eth_blockNumber", ()).await.unwrap();
+        // the mock has no responses left
+        assert!(recorder.request::<_, U64>("eth_chainId", ()).await.is_err());
+
+        let replay = ReplayClient::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let replayed: Vec<U64> = vec![
+            replay.request("eth_blockNumber", ()).await.unwrap(),
+            replay.request("eth_blockNumber", ()).await.unwrap(),
+            replay.request("eth_blockNumber", ()).await.unwrap(),
+        ];
+        assert_eq!(replayed, vec![first, second, second]);
+
+        assert!(matches!(
+            replay.request::<_, U64>("eth_chainId", ()).await,
+            Err(RecordError::RecordedFailure { .. })
+        ));
+        assert!(matches!(
+            replay.request::<_, U64>("eth_blockNumber", [1]).await,
+            Err(RecordError::NotRecorded { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_recording() {
+        let path = recording_path("invalid");
+        std::fs::write(
+            &path,
+            "{\"method\": \"eth_chainId\", \"params\": null}\nnot json\n",
+        )
+        .unwrap();
+
+        let result = ReplayClient::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(RecordError::ParseRecording { line: 2, .. })
+        ));
+    }
+}
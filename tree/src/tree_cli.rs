@@ -0,0 +1,173 @@
+#![warn(unused_extern_crates)]
+use tree::initial_state::{parse_address, parse_number};
+use tree::log_dump::{parse_dump, Dump, DumpFilter};
+
+use serde_json::Value;
+use std::io::Read;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Inspect a tree offline, rebuilt from a dump of its `VertexInserted` logs
+#[derive(Debug, StructOpt)]
+#[structopt(name = "tree")]
+struct TreeOpt {
+    /// File with the `eth_getLogs` logs of the tree, or the parent of each
+    /// vertex in insertion order, `-` reading it from stdin
+    #[structopt(parse(from_os_str))]
+    dump: PathBuf,
+
+    /// Address of the tree contract, when the dump holds logs of several
+    #[structopt(long)]
+    address: Option<String>,
+
+    /// Indexed `_id` of the tree, when the dump holds logs of several
+    #[structopt(long)]
+    id: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Size, deepest and last vertex, and blocks spanned by the logs
+    Info,
+    /// Deepest vertex, the oldest one when several are as deep
+    Deepest,
+    /// Ancestor of a vertex at a depth
+    Ancestor { index: u32, depth: u32 },
+    /// Vertex, and whether it is on the deepest path at least `distance`
+    /// vertices above the deepest one
+    Status {
+        index: u32,
+        #[structopt(long, default_value = "0")]
+        distance: u32,
+    },
+    /// Draw the tree
+    Render,
+    /// Print the tree as JSON, or as the parent of each vertex with
+    /// `--parents`
+    Export {
+        #[structopt(long)]
+        parents: bool,
+    },
+}
+
+fn main() {
+    let opt = TreeOpt::from_args();
+
+    match run(opt) {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(opt: TreeOpt) -> Result<String, Box<dyn std::error::Error>> {
+    let filter = DumpFilter {
+        tree_address: match opt.address {
+            Some(address) => {
+                Some(parse_address("address", &Value::String(address))?)
+            }
+            None => None,
+        },
+        identifier: match opt.id {
+            Some(id) => Some(parse_number("id", &Value::String(id))?),
+            None => None,
+        },
+    };
+
+    let mut text = String::new();
+    if opt.dump.as_os_str() == "-" {
+        std::io::stdin().read_to_string(&mut text)?;
+    } else {
+        text = std::fs::read_to_string(&opt.dump).map_err(|e| {
+            format!("could not read {}: {}", opt.dump.display(), e)
+        })?;
+    }
+
+    let dump = parse_dump(&text, &filter)?;
+    let tree = &dump.tree;
+
+    Ok(match opt.command {
+        Command::Info => info(&dump),
+        Command::Deepest => match tree.get_deepest() {
+            Some(index) => describe(&dump, index),
+            None => "empty tree\n".to_string(),
+        },
+        Command::Ancestor { index, depth } => {
+            describe(&dump, tree.get_ancestor_rc_at(index, depth)?.get_index())
+        }
+        Command::Status { index, distance } => {
+            if tree.get_vertex(index).is_none() {
+                return Err(format!(
+                    "vertex {} not found in tree of {} vertices",
+                    index,
+                    tree.size()
+                )
+                .into());
+            }
+            format!(
+                "{}valid with distance {}: {}\n",
+                describe(&dump, index),
+                distance,
+                tree.is_valid_vertex_with_distance(index, distance)
+            )
+        }
+        Command::Render => dump.render(),
+        Command::Export { parents: true } => dump
+            .parents()
+            .iter()
+            .map(|parent| format!("{}\n", parent))
+            .collect(),
+        Command::Export { parents: false } => {
+            format!("{}\n", serde_json::to_string_pretty(&dump)?)
+        }
+    })
+}
+
+fn info(dump: &Dump) -> String {
+    let tree = &dump.tree;
+    let mut out = format!("vertices: {}\n", tree.size());
+
+    if let Some(deepest) = tree.get_deepest() {
+        out += &format!(
+            "deepest: {} (depth {})\n",
+            deepest,
+            tree.get_vertex(deepest).unwrap().get_depth()
+        );
+    }
+    if let Some(last) = tree.get_last() {
+        out += &format!("last: {}\n", last);
+    }
+
+    let blocks = dump.provenance.values().map(|p| p.block_number);
+    if let (Some(first), Some(last)) = (blocks.clone().min(), blocks.max()) {
+        out += &format!("blocks: {} to {}\n", first, last);
+    }
+
+    out
+}
+
+/// Index, depth and parent of a vertex, and where it was inserted
+fn describe(dump: &Dump, index: u32) -> String {
+    let vertex = dump.tree.get_vertex(index).unwrap();
+    let mut out = format!("vertex: {}\ndepth: {}\n", index, vertex.get_depth());
+
+    if let Some(parent) = vertex.get_parent() {
+        out += &format!("parent: {}\n", parent);
+    }
+    if let Some(provenance) = dump.provenance.get(&index) {
+        out += &format!(
+            "block: {} ({:?})\ntransaction: {:?}\nlog index: {}\n",
+            provenance.block_number,
+            provenance.block_hash,
+            provenance.tx_hash,
+            provenance.log_index
+        );
+    }
+
+    out
+}
@@ -2,7 +2,6 @@ use crate::error::*;
 
 use im::{HashMap, OrdSet};
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
 use std::cmp::Ordering;
 use std::sync::Arc;
 
@@ -78,8 +77,9 @@ impl Tree {
             if let Some(parent_vertex) = parent_vertex_opt {
                 depth = parent_vertex.depth + 1;
             } else {
-                return TreeMalformed {
-                    err: "Incoming vertex doesn't have a valid parent",
+                return ParentNotFound {
+                    parent: parent_index,
+                    tree_size: self.size(),
                 }
                 .fail();
             }
@@ -116,8 +116,10 @@ impl Tree {
                 Ok(vertex)
             } else if vertex_depth < depth {
                 // invalid index or depth
-                VertexNotFound {
-                    err: "Vertex is not deeper than ancestor",
+                AncestorDeeperThanVertex {
+                    index,
+                    vertex_depth,
+                    depth,
                 }
                 .fail()
             } else {
@@ -128,11 +130,7 @@ impl Tree {
                     if let Some(parent) = parent_opt {
                         let parent_vertex = self
                             .get_vertex_rc(parent)
-                            .clone()
-                            .ok_or(snafu::NoneError)
-                            .context(TreeMalformed {
-                                err: "Ancestor at depth not found",
-                            })?;
+                            .ok_or(Error::AncestorNotFound { index, depth })?;
 
                         if parent_vertex.depth <= depth {
                             break;
@@ -147,15 +145,13 @@ impl Tree {
                 parent_opt
                     .and_then(|p| self.get_vertex_rc(p))
                     .filter(|p| p.depth == depth)
-                    .ok_or(snafu::NoneError)
-                    .context(TreeMalformed {
-                        err: "Ancestor at depth not found",
-                    })
+                    .ok_or(Error::AncestorNotFound { index, depth })
             }
         } else {
             // vertex not exist at index
             return VertexNotFound {
-                err: "Invalid index",
+                index,
+                tree_size: self.size(),
             }
             .fail();
         }
@@ -216,10 +212,29 @@ impl Tree {
     pub fn size(&self) -> usize {
         self.vertices.len()
     }
+
+    /// get tree with only the first `size` vertices, undoing the later
+    /// insertions
+    pub fn truncate(&self, size: usize) -> Self {
+        let mut vertices = self.vertices.clone();
+        let mut deepest = self.deepest.clone();
+
+        for index in size..self.size() {
+            if let Some(vertex) = vertices.remove(&(index as u32)) {
+                deepest.remove(&VertexKey {
+                    depth: vertex.depth,
+                    index: vertex.index,
+                });
+            }
+        }
+
+        Tree { vertices, deepest }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::Error;
     use crate::tree_lib::Tree;
 
     #[test]
@@ -228,7 +243,41 @@ mod tests {
         assert!(tree.is_ok(), "Insert Genesis Block should pass");
 
         let tree = tree.unwrap().insert_vertex(5);
-        assert!(tree.is_err(), "Insert invalid parent should fail");
+        assert!(
+            matches!(
+                tree,
+                Err(Error::ParentNotFound {
+                    parent: 5,
+                    tree_size: 1
+                })
+            ),
+            "Insert invalid parent should fail"
+        );
+    }
+
+    #[test]
+    fn test_ancestor_errors() {
+        let mut tree = Tree::default().insert_vertex(0).unwrap();
+        for i in 0u32..3 {
+            tree = tree.insert_vertex(i).unwrap();
+        }
+
+        assert!(matches!(
+            tree.get_ancestor_rc_at(7, 0),
+            Err(Error::VertexNotFound {
+                index: 7,
+                tree_size: 4
+            })
+        ));
+        assert!(matches!(
+            tree.get_ancestor_rc_at(2, 3),
+            Err(Error::AncestorDeeperThanVertex {
+                index: 2,
+                vertex_depth: 2,
+                depth: 3
+            })
+        ));
+        assert_eq!(tree.get_ancestor_rc_at(3, 1).unwrap().get_index(), 1);
     }
 
     #[test]
@@ -287,6 +336,45 @@ mod tests {
         assert!(deepest.unwrap() == 21, "Deepest vertex should match");
     }
 
+    #[test]
+    fn test_truncate() {
+        let mut tree = Tree::default().insert_vertex(0).unwrap();
+        for i in 0u32..10 {
+            tree = tree.insert_vertex(i).unwrap();
+        }
+        let branch = tree.clone();
+        for _ in 0u32..5 {
+            tree = tree.insert_vertex(10).unwrap();
+        }
+        tree = tree.insert_vertex(11).unwrap();
+
+        let truncated = tree.truncate(branch.size());
+        assert!(truncated.size() == 11, "Tree size should match");
+        assert!(
+            truncated.get_deepest() == branch.get_deepest(),
+            "Deepest vertex should roll back"
+        );
+        assert!(
+            truncated.get_vertex(11).is_none(),
+            "Truncated vertex should not exist"
+        );
+
+        let replaced = truncated.insert_vertex(3).unwrap();
+        assert!(
+            replaced.get_vertex(11).unwrap().get_depth() == 4,
+            "Replacement vertex should take the truncated index"
+        );
+
+        assert!(
+            tree.truncate(100).size() == tree.size(),
+            "Truncating past the end should keep all vertices"
+        );
+        assert!(
+            tree.truncate(0).get_deepest().is_none(),
+            "Truncating to zero should empty the tree"
+        );
+    }
+
     #[test]
     fn test_ancestor() {
         let mut tree = Tree::default().insert_vertex(0).unwrap();
@@ -0,0 +1,524 @@
+use crate::deadline::{timeout_of, with_deadline};
+use crate::error::Error;
+use crate::fold::tree_delegate::{TreeKey, TreeState};
+use crate::initial_state::{parse_address, parse_initial_state, parse_number};
+use crate::logging::in_request_span;
+use crate::tree_lib::{Tree, Vertex};
+use crate::tree_server::TreeDelegateManager;
+use crate::tree_updates::{diff, snapshot};
+
+use proto::block_selector::Selector;
+use proto::tree_query_server::TreeQuery;
+use proto::*;
+
+use state_fold::{types::QueryBlock, Foldable};
+
+use ethers::core::types::{H256, U64};
+use ethers::providers::Middleware;
+use futures::stream::{self, StreamExt};
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Request, Response, Status};
+use tracing::{debug, Instrument, Span};
+
+/// Updates buffered for each subscribed client
+const UPDATE_CHANNEL_CAPACITY: usize = 64;
+
+pub mod proto {
+    tonic::include_proto!("TreeServer");
+}
+
+#[tonic::async_trait]
+impl<M: Middleware + 'static> TreeQuery for TreeDelegateManager<M> {
+    type SubscribeTreeStream =
+        ReceiverStream<std::result::Result<TreeUpdate, Status>>;
+
+    async fn get_deepest(
+        &self,
+        request: Request<GetDeepestRequest>,
+    ) -> std::result::Result<Response<GetDeepestResponse>, Status> {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "GetDeepest",
+            with_deadline(timeout, async move {
+                let request = request.into_inner();
+                let state =
+                    self.query_state(request.tree, request.block).await?;
+
+                let deepest = state
+                    .tree
+                    .as_ref()
+                    .and_then(|tree| {
+                        tree.get_deepest().map(|index| (tree, index))
+                    })
+                    .and_then(|(tree, index)| tree.get_vertex(index))
+                    .map(to_proto_vertex);
+
+                Ok(Response::new(GetDeepestResponse {
+                    block: Some(to_proto_block(&state)),
+                    deepest,
+                }))
+            }),
+        )
+        .await
+    }
+
+    async fn get_vertex(
+        &self,
+        request: Request<GetVertexRequest>,
+    ) -> std::result::Result<Response<GetVertexResponse>, Status> {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "GetVertex",
+            with_deadline(timeout, async move {
+                let request = request.into_inner();
+                let state =
+                    self.query_state(request.tree, request.block).await?;
+
+                let tree = tree_of(&state)?;
+                let vertex = tree
+                    .get_vertex(request.index)
+                    .map(to_proto_vertex)
+                    .ok_or(Error::VertexNotFound {
+                        index: request.index,
+                        tree_size: tree.size(),
+                    })?;
+
+                Ok(Response::new(GetVertexResponse {
+                    block: Some(to_proto_block(&state)),
+                    vertex: Some(vertex),
+                }))
+            }),
+        )
+        .await
+    }
+
+    async fn get_ancestor_at_depth(
+        &self,
+        request: Request<GetAncestorAtDepthRequest>,
+    ) -> std::result::Result<Response<GetAncestorAtDepthResponse>, Status> {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "GetAncestorAtDepth",
+            with_deadline(timeout, async move {
+                let request = request.into_inner();
+                let state =
+                    self.query_state(request.tree, request.block).await?;
+
+                let ancestor = tree_of(&state)?
+                    .get_ancestor_rc_at(request.index, request.depth)
+                    .map_err(Status::from)?;
+
+                Ok(Response::new(GetAncestorAtDepthResponse {
+                    block: Some(to_proto_block(&state)),
+                    ancestor: Some(to_proto_vertex(&ancestor)),
+                }))
+            }),
+        )
+        .await
+    }
+
+    async fn get_tree_size(
+        &self,
+        request: Request<GetTreeSizeRequest>,
+    ) -> std::result::Result<Response<GetTreeSizeResponse>, Status> {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "GetTreeSize",
+            with_deadline(timeout, async move {
+                let request = request.into_inner();
+                let state =
+                    self.query_state(request.tree, request.block).await?;
+
+                let size =
+                    state.tree.as_ref().map(|tree| tree.size()).unwrap_or(0);
+
+                Ok(Response::new(GetTreeSizeResponse {
+                    block: Some(to_proto_block(&state)),
+                    size: size as u64,
+                }))
+            }),
+        )
+        .await
+    }
+
+    async fn is_valid_vertex_with_distance(
+        &self,
+        request: Request<IsValidVertexWithDistanceRequest>,
+    ) -> std::result::Result<Response<IsValidVertexWithDistanceResponse>, Status>
+    {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "IsValidVertexWithDistance",
+            with_deadline(timeout, async move {
+                let request = request.into_inner();
+                let state =
+                    self.query_state(request.tree, request.block).await?;
+
+                let valid = state
+                    .tree
+                    .as_ref()
+                    .map(|tree| {
+                        tree.is_valid_vertex_with_distance(
+                            request.index,
+                            request.distance,
+                        )
+                    })
+                    .unwrap_or(false);
+
+                Ok(Response::new(IsValidVertexWithDistanceResponse {
+                    block: Some(to_proto_block(&state)),
+                    valid,
+                }))
+            }),
+        )
+        .await
+    }
+
+    async fn get_states(
+        &self,
+        request: Request<GetStatesRequest>,
+    ) -> std::result::Result<Response<GetStatesResponse>, Status> {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "GetStates",
+            with_deadline(timeout, async move {
+                let request = request.into_inner();
+                let response = self
+                    .batch_states(&request.json_initial_states, request.block)
+                    .await?;
+
+                Ok(Response::new(response))
+            }),
+        )
+        .await
+    }
+
+    async fn subscribe_tree(
+        &self,
+        request: Request<SubscribeTreeRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeTreeStream>, Status> {
+        let timeout = timeout_of(&request, self.request_timeout);
+        in_request_span(
+            "SubscribeTree",
+            with_deadline(timeout, async move {
+                let updates =
+                    self.subscribe_tree_updates(request.into_inner()).await?;
+
+                Ok(Response::new(ReceiverStream::new(updates)))
+            }),
+        )
+        .await
+    }
+}
+
+impl<M: Middleware + 'static> TreeDelegateManager<M> {
+    /// Sends the first updates of a subscription, then forwards the diff of
+    /// every folded state until the client goes away
+    async fn subscribe_tree_updates(
+        &self,
+        request: SubscribeTreeRequest,
+    ) -> std::result::Result<
+        mpsc::Receiver<std::result::Result<TreeUpdate, Status>>,
+        Status,
+    > {
+        let key = parse_locator(request.tree)?;
+        let folded_states = self.folded_states.clone().ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                "Tree subscriptions require the server to follow new heads",
+            )
+        })?;
+
+        // subscribe before computing the current state so no fold is missed
+        let mut folds = folded_states.subscribe(key);
+        let current = self.state_at(key, QueryBlock::Latest).await?;
+
+        let first_updates = match self.resume_state(key, &request).await? {
+            Some(resumed) => diff(&resumed, &current),
+            None => vec![snapshot(&current)],
+        };
+
+        let (tx, rx) = mpsc::channel(UPDATE_CHANNEL_CAPACITY);
+        tokio::spawn(
+            async move {
+                for update in first_updates {
+                    if tx.send(Ok(update)).await.is_err() {
+                        debug!("Subscriber went away");
+                        return;
+                    }
+                }
+
+                let mut last = current;
+                loop {
+                    let next = match folds.recv().await {
+                        Ok(next) => next,
+                        // only the latest state matters, diffs are cumulative
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    };
+
+                    for update in diff(&last, &next) {
+                        if tx.send(Ok(update)).await.is_err() {
+                            debug!("Subscriber went away");
+                            return;
+                        }
+                    }
+                    last = next;
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        Ok(rx)
+    }
+
+    /// State at the block a client resumes from, if any
+    async fn resume_state(
+        &self,
+        key: TreeKey,
+        request: &SubscribeTreeRequest,
+    ) -> std::result::Result<Option<TreeState>, Status> {
+        if request.resume_block_hash.is_empty() {
+            return Ok(None);
+        }
+
+        let hash = parse_block_selector(Some(BlockSelector {
+            selector: Some(Selector::Hash(request.resume_block_hash.clone())),
+        }))?;
+
+        // a block unknown to the node falls back to a snapshot
+        Ok(TreeState::get_state_for_block(
+            &self.initial_state(key),
+            hash,
+            &self.env,
+        )
+        .await
+        .ok()
+        .map(|block_state| block_state.state))
+    }
+
+    /// States of many trees at the same block, the block `block` resolves
+    /// to, computed up to `batch_concurrency` at once. Initial states
+    /// selecting their own `block` fail with `InvalidArgument`, the block
+    /// being the batch's.
+    async fn batch_states(
+        &self,
+        json_initial_states: &[String],
+        block: Option<BlockSelector>,
+    ) -> std::result::Result<GetStatesResponse, Status> {
+        let query_block = parse_block_selector(block)?;
+        let (number, hash) = self.resolve_block(query_block).await?;
+
+        let mut answered: Vec<_> = stream::iter(json_initial_states.iter())
+            .enumerate()
+            .map(|(i, json)| async move {
+                let result = match parse_initial_state(json) {
+                    Ok(initial_state) if initial_state.block.is_some() => {
+                        Err(Status::new(
+                            Code::InvalidArgument,
+                            "Invalid initial state field `block`: set by \
+                             the `GetStates` request",
+                        ))
+                    }
+                    Ok(initial_state) => self
+                        .json_state_at(
+                            initial_state.key,
+                            QueryBlock::BlockHash(hash),
+                            initial_state.version,
+                        )
+                        .await
+                        .map(|(_, json_state)| json_state),
+                    Err(e) => Err(e.into()),
+                };
+                (i, result)
+            })
+            .buffer_unordered(self.batch_concurrency.max(1))
+            .collect()
+            .await;
+        answered.sort_by_key(|(i, _)| *i);
+
+        Ok(GetStatesResponse {
+            block: Some(proto::Block {
+                number: number.as_u64(),
+                hash: format!("{:?}", hash),
+            }),
+            states: answered
+                .into_iter()
+                .map(|(_, result)| to_proto_result(result))
+                .collect(),
+        })
+    }
+
+    pub(crate) async fn query_state(
+        &self,
+        tree: Option<TreeLocator>,
+        block: Option<BlockSelector>,
+    ) -> std::result::Result<TreeState, Status> {
+        let key = parse_locator(tree)?;
+        let query_block = parse_block_selector(block)?;
+
+        self.state_at(key, query_block).await
+    }
+}
+
+/// Parse the tree locator into a `TreeState` initial state. The
+/// `pos_instance` is validated, and ignored as in `GetState` initial states.
+pub fn parse_locator(
+    tree: Option<TreeLocator>,
+) -> std::result::Result<TreeKey, Status> {
+    let tree = tree.ok_or_else(|| {
+        Status::new(Code::InvalidArgument, "Missing tree locator")
+    })?;
+
+    let tree_address =
+        parse_address("tree_address", &Value::String(tree.tree_address))?;
+    if !tree.pos_instance.is_empty() {
+        parse_number("pos_instance", &Value::String(tree.pos_instance))?;
+    }
+
+    Ok(TreeKey::new(tree_address))
+}
+
+/// Parse the block selector, defaulting to the latest block
+pub fn parse_block_selector(
+    block: Option<BlockSelector>,
+) -> std::result::Result<QueryBlock, Status> {
+    match block.and_then(|block| block.selector) {
+        None => Ok(QueryBlock::Latest),
+        Some(Selector::Number(number)) => {
+            Ok(QueryBlock::BlockNumber(U64::from(number)))
+        }
+        Some(Selector::Hash(hash)) => H256::from_str(strip_hex_prefix(&hash))
+            .map(QueryBlock::BlockHash)
+            .map_err(|e| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("Invalid block hash `{}`: {}", hash, e),
+                )
+            }),
+        Some(Selector::Confirmations(confirmations)) => {
+            Ok(QueryBlock::BlockDepth(confirmations as usize))
+        }
+    }
+}
+
+fn strip_hex_prefix(value: &str) -> &str {
+    value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value)
+}
+
+fn tree_of(state: &TreeState) -> std::result::Result<&Tree, Status> {
+    state
+        .tree
+        .as_ref()
+        .ok_or_else(|| Error::TreeEmpty {}.into())
+}
+
+pub(crate) fn to_proto_vertex(vertex: &Vertex) -> proto::Vertex {
+    proto::Vertex {
+        index: vertex.get_index(),
+        depth: vertex.get_depth(),
+        parent: vertex.get_parent(),
+    }
+}
+
+fn to_proto_result(
+    result: std::result::Result<Arc<String>, Status>,
+) -> TreeStateResult {
+    let result = match result {
+        Ok(json_state) => {
+            tree_state_result::Result::JsonState(json_state.to_string())
+        }
+        Err(status) => tree_state_result::Result::Error(TreeStateError {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        }),
+    };
+
+    TreeStateResult {
+        result: Some(result),
+    }
+}
+
+pub(crate) fn to_proto_block(state: &TreeState) -> proto::Block {
+    proto::Block {
+        number: state.block_number.as_u64(),
+        hash: format!("{:?}", state.block_hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ethers::core::types::Address;
+
+    #[test]
+    fn test_parse_locator() {
+        let key = parse_locator(Some(TreeLocator {
+            tree_address: "0x5FbDB2315678afecb367f032d93F642f64180aa3".into(),
+            pos_instance: "42".into(),
+        }))
+        .unwrap();
+        assert_eq!(
+            key,
+            TreeKey::new(
+                Address::from_str("5fbdb2315678afecb367f032d93f642f64180aa3")
+                    .unwrap()
+            ),
+            "Pos instance is ignored"
+        );
+
+        assert!(parse_locator(None).is_err(), "Locator is required");
+        assert!(parse_locator(Some(TreeLocator {
+            tree_address: "0x1234".into(),
+            pos_instance: "".into(),
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_to_proto_result() {
+        let ok = to_proto_result(Ok(Arc::new("{}".to_string())));
+        assert_eq!(
+            ok.result,
+            Some(tree_state_result::Result::JsonState("{}".to_string()))
+        );
+
+        let failed = to_proto_result(Err(Error::TreeEmpty {}.into()));
+        assert_eq!(
+            failed.result,
+            Some(tree_state_result::Result::Error(TreeStateError {
+                code: Code::NotFound as i32,
+                message: "Tree is empty".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_block_selector() {
+        assert!(matches!(parse_block_selector(None), Ok(QueryBlock::Latest)));
+        assert!(matches!(
+            parse_block_selector(Some(BlockSelector {
+                selector: Some(Selector::Number(10)),
+            })),
+            Ok(QueryBlock::BlockNumber(n)) if n == U64::from(10)
+        ));
+        assert!(matches!(
+            parse_block_selector(Some(BlockSelector {
+                selector: Some(Selector::Confirmations(3)),
+            })),
+            Ok(QueryBlock::BlockDepth(3))
+        ));
+        assert!(parse_block_selector(Some(BlockSelector {
+            selector: Some(Selector::Hash("0xzz".into())),
+        }))
+        .is_err());
+    }
+}
@@ -1,58 +1,404 @@
-use crate::fold::tree_delegate::TreeState;
+use crate::config::TreeServerConfig;
+use crate::deadline::{timeout_of, with_deadline};
+use crate::error::{code_of, BlockNotFound, TreeUnavailable};
+use crate::fold::tree_delegate::{
+    FoldConfig, TreeInitialState, TreeKey, TreeState, VertexProvenance,
+};
+use crate::folded_states::FoldedStates;
+use crate::in_flight::InFlight;
+use crate::initial_state::{parse_initial_state, InitialState};
+use crate::logging::in_request_span;
+use crate::metrics;
+use crate::response_cache::ResponseCache;
+use crate::tree_lib::Tree;
 
 use state_fold::{types::QueryBlock, Foldable, StateFoldEnvironment};
 use state_server_grpc::state_server::delegate_manager_server::DelegateManager;
 use state_server_grpc::state_server::{GetStateRequest, GetStateResponse};
 
-use ethers::core::types::{Address, U256};
-use ethers::providers::{Http, Provider};
-use serde::{Deserialize, Serialize};
+use ethers::core::types::{BlockId, BlockNumber, H256, U64};
+use ethers::providers::Middleware;
+use im::HashMap;
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tonic::{Code, Request, Response, Status};
+use tracing::{debug, field, Span};
 
-pub struct TreeDelegateManager {
-    pub env: Arc<StateFoldEnvironment<Provider<Http>>>,
+pub struct TreeDelegateManager<M: Middleware + 'static> {
+    pub env: Arc<StateFoldEnvironment<M>>,
+    // node the environment queries, to resolve blocks of batches
+    pub provider: Arc<M>,
+    // how trees are fetched, passed to every sync
+    pub fold_config: Arc<FoldConfig>,
+    // latest states folded ahead of time, when following new heads
+    pub folded_states: Option<Arc<FoldedStates>>,
+    // serialized responses by tree and block
+    pub response_cache: ResponseCache,
+    // states being computed by tree and queried block
+    pub in_flight: InFlight<(TreeKey, BlockKey), TreeState>,
+    // longest time to answer a request, on top of the client deadline
+    pub request_timeout: Option<Duration>,
+    // trees computed at once by a batch request
+    pub batch_concurrency: usize,
+    // blocks a folded state can lag behind the head and still be the latest
+    pub safety_margin: usize,
 }
 
-#[derive(Deserialize, Serialize)]
-struct InitialState {
-    pub pos_instance: U256,
-    pub tree_address: Address,
+/// Block a state is queried at, as the key of its computation in flight
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BlockKey {
+    Latest,
+    Hash(H256),
+    Number(U64),
+    Depth(usize),
+}
+
+impl BlockKey {
+    /// Key of `query_block`, if its computations can be shared
+    fn of(query_block: &QueryBlock) -> Option<Self> {
+        match query_block {
+            QueryBlock::Latest => Some(BlockKey::Latest),
+            QueryBlock::BlockHash(hash) => Some(BlockKey::Hash(*hash)),
+            QueryBlock::BlockNumber(number) => Some(BlockKey::Number(*number)),
+            QueryBlock::BlockDepth(depth) => Some(BlockKey::Depth(*depth)),
+            _ => None,
+        }
+    }
+}
+
+/// Tree returned to clients of version 2 initial states and of the HTTP
+/// gateway, with the on-chain origin of each vertex and the block the tree
+/// was computed at. Version 1 clients get the `tree` alone, `null` when empty.
+#[derive(Serialize)]
+pub(crate) struct TreeResponse<'a> {
+    tree: &'a Option<Tree>,
+    provenance: &'a HashMap<u32, VertexProvenance>,
+    block_number: U64,
+    block_hash: H256,
+}
+
+impl<'a> TreeResponse<'a> {
+    pub(crate) fn new(state: &'a TreeState) -> Self {
+        TreeResponse {
+            tree: &state.tree,
+            provenance: &state.provenance,
+            block_number: state.block_number,
+            block_hash: state.block_hash,
+        }
+    }
 }
 
 #[tonic::async_trait]
-impl DelegateManager for TreeDelegateManager {
+impl<M: Middleware + 'static> DelegateManager for TreeDelegateManager<M> {
     async fn get_state(
         &self,
         request: Request<GetStateRequest>,
+    ) -> std::result::Result<Response<GetStateResponse>, Status> {
+        let start = Instant::now();
+        let timeout = timeout_of(&request, self.request_timeout);
+        let result = in_request_span(
+            "GetState",
+            with_deadline(timeout, self.json_state(request)),
+        )
+        .await;
+
+        let code = result.as_ref().err().map_or(Code::Ok, Status::code);
+        metrics::observe_request("GetState", code, start.elapsed());
+
+        result
+    }
+}
+
+impl<M: Middleware + 'static> TreeDelegateManager<M> {
+    /// Manager querying the chain through `provider`, any middleware stack
+    /// over the node, with the environment, cache and limits of `config`
+    pub fn new(
+        provider: Arc<M>,
+        config: &TreeServerConfig,
+        folded_states: Option<Arc<FoldedStates>>,
+    ) -> Self {
+        TreeDelegateManager {
+            env: Arc::new(new_environment(Arc::clone(&provider), config)),
+            provider,
+            fold_config: Arc::new(FoldConfig {
+                log_query: config.log_query.clone(),
+                verify: config.verify.clone(),
+                fetch_senders: config.fetch_senders,
+            }),
+            folded_states,
+            response_cache: ResponseCache::new(config.response_cache_bytes),
+            in_flight: InFlight::new(),
+            request_timeout: Some(config.request_timeout),
+            batch_concurrency: config.batch_concurrency,
+            safety_margin: config.safety_margin,
+        }
+    }
+
+    /// Initial state of the tree of `key`, fetched as configured
+    pub(crate) fn initial_state(&self, key: TreeKey) -> TreeInitialState {
+        TreeInitialState::new(key, Arc::clone(&self.fold_config))
+    }
+
+    /// State of the tree in the request at its selected block, serialized as
+    /// JSON
+    async fn json_state(
+        &self,
+        request: Request<GetStateRequest>,
     ) -> std::result::Result<Response<GetStateResponse>, Status> {
         let client = request.remote_addr();
         let initial_state = request.into_inner().json_initial_state;
 
-        println!(
-            "Got a request from {:?}, initial state: {}",
-            client, initial_state
-        );
+        debug!(client = ?client, "Got a request");
+
+        let InitialState {
+            version,
+            key,
+            block,
+        } = parse_initial_state(&initial_state)?;
+        let block = block.unwrap_or(QueryBlock::Latest);
+        let (_, json_state) = self.json_state_at(key, block, version).await?;
 
-        let initial_state: InitialState = serde_json::from_str(&initial_state)
-            .map_err(|e| {
-                Status::new(Code::InvalidArgument, format!("{}", e))
+        let reply = GetStateResponse {
+            json_state: json_state.to_string(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    /// State of tree at `query_block` and its JSON serialization in the
+    /// response format of initial state `version`, cached per tree, block and
+    /// format
+    pub(crate) async fn json_state_at(
+        &self,
+        key: TreeKey,
+        query_block: QueryBlock,
+        version: u64,
+    ) -> std::result::Result<(TreeState, Arc<String>), Status> {
+        let contract_state = self.state_at(key, query_block).await?;
+
+        let json_state = self
+            .response_cache
+            .get_or_try_insert_with(
+                (key, contract_state.block_hash, version),
+                || async {
+                    match version {
+                        1 => serde_json::to_string(&contract_state.tree),
+                        _ => serde_json::to_string(&TreeResponse::new(
+                            &contract_state,
+                        )),
+                    }
+                    .map_err(|e| Status::new(Code::Unknown, format!("{}", e)))
+                },
+            )
+            .await?;
+
+        Ok((contract_state, json_state))
+    }
+
+    /// State of tree at `query_block`, tagged with the block it was computed
+    /// at. States at the block of the folded state, or the latest one while
+    /// the folded state is within `safety_margin` blocks of the head, come
+    /// from the folded states when available. Trees computed on demand at the
+    /// latest block are folded from then on only if they are tracked from
+    /// startup or subscribed to, so requests can't grow the folded trees.
+    /// Concurrent requests for the same tree and block share the computation
+    /// of its state.
+    pub(crate) async fn state_at(
+        &self,
+        key: TreeKey,
+        query_block: QueryBlock,
+    ) -> std::result::Result<TreeState, Status> {
+        let latest = matches!(query_block, QueryBlock::Latest);
+        Span::current().record("tree", &field::debug(key.tree_address));
+
+        if let Some(folded_states) = &self.folded_states {
+            if let Some(state) = folded_states.get(&key).await {
+                let head = folded_states.head();
+                if is_state_at(&state, &query_block, head, self.safety_margin) {
+                    record_block(&state);
+                    return Ok(state);
+                }
+            }
+        }
+
+        let block = BlockKey::of(&query_block);
+        let initial_state = &self.initial_state(key);
+        let env = &self.env;
+        let compute = move || async move {
+            TreeState::get_state_for_block(initial_state, query_block, env)
+                .await
+                .map(|state| state.state)
+                .map_err(|e| Status::new(code_of(&e), format!("{}", e)))
+        };
+        let state = match block {
+            Some(block) => {
+                self.in_flight.get_or_try_run((key, block), compute).await?
+            }
+            None => compute().await?,
+        };
+
+        // followed trees are folded from then on, and only folded trees,
+        // bounded in number, are exported as metrics
+        if latest {
+            if let Some(folded_states) = &self.folded_states {
+                if folded_states.is_followed(&key) {
+                    folded_states.insert(key, state.clone()).await;
+                }
+            }
+        }
+
+        record_block(&state);
+        Ok(state)
+    }
+
+    /// Number and hash of the block `query_block` selects, the latest block
+    /// being `safety_margin` blocks behind the head as in state folds
+    pub(crate) async fn resolve_block(
+        &self,
+        query_block: QueryBlock,
+    ) -> std::result::Result<(U64, H256), Status> {
+        let id = match query_block {
+            QueryBlock::BlockHash(hash) => BlockId::Hash(hash),
+            QueryBlock::BlockNumber(number) => {
+                BlockId::Number(BlockNumber::Number(number))
+            }
+            QueryBlock::Latest => self.behind_head(self.safety_margin).await?,
+            QueryBlock::BlockDepth(depth) => self.behind_head(depth).await?,
+            _ => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Unsupported block selector",
+                ))
+            }
+        };
+
+        let block = self
+            .provider
+            .get_block(id)
+            .await
+            .map_err(|e| e.into())
+            .context(TreeUnavailable {
+                err: "Error getting the block",
+            })?;
+        let block = block.and_then(|block| Some((block.number?, block.hash?)));
+        Ok(block.context(BlockNotFound {
+            block: format!("{:?}", id),
+        })?)
+    }
+
+    /// Block `depth` blocks behind the head
+    async fn behind_head(
+        &self,
+        depth: usize,
+    ) -> std::result::Result<BlockId, Status> {
+        let head = self
+            .provider
+            .get_block_number()
+            .await
+            .map_err(|e| e.into())
+            .context(TreeUnavailable {
+                err: "Error getting the head",
             })?;
 
-        let contract_state = TreeState::get_state_for_block(
-            &(initial_state.pos_instance, initial_state.tree_address),
-            QueryBlock::Latest,
-            &self.env,
+        Ok(BlockId::Number(BlockNumber::Number(
+            head.saturating_sub(U64::from(depth)),
+        )))
+    }
+}
+
+/// State fold environment over `provider` configured by `config`
+pub fn new_environment<M: Middleware + 'static>(
+    provider: Arc<M>,
+    config: &TreeServerConfig,
+) -> StateFoldEnvironment<M> {
+    StateFoldEnvironment::new(
+        provider,
+        config.safety_margin,
+        config.genesis_block,
+        config.query_limit_error_codes.clone(),
+        config.concurrent_events_fetch,
+    )
+}
+
+/// Whether `state` is the state of its tree at `query_block`. A folded state
+/// is the latest one until it lags more than `safety_margin` blocks behind
+/// `head`, the latest block folded, having failed to fold since.
+fn is_state_at(
+    state: &TreeState,
+    query_block: &QueryBlock,
+    head: Option<U64>,
+    safety_margin: usize,
+) -> bool {
+    match query_block {
+        QueryBlock::Latest => head.map_or(true, |head| {
+            head.saturating_sub(state.block_number) <= U64::from(safety_margin)
+        }),
+        QueryBlock::BlockHash(hash) => *hash == state.block_hash,
+        QueryBlock::BlockNumber(number) => *number == state.block_number,
+        _ => false,
+    }
+}
+
+/// Record the block of `state` in the current request span
+fn record_block(state: &TreeState) {
+    Span::current().record("block_number", &state.block_number.as_u64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{TreeServerFileConfig, TreeServerOpt};
+
+    use ethers::providers::{MockProvider, Provider};
+
+    #[test]
+    fn test_new_from_config() {
+        let config = TreeServerConfig::merge(
+            TreeServerOpt {
+                batch_concurrency: Some(3),
+                request_timeout: Some(5),
+                ..Default::default()
+            },
+            TreeServerFileConfig::default(),
         )
-        .await
-        .map_err(|e| Status::new(Code::Unavailable, format!("{}", e)))?
-        .state;
+        .unwrap();
 
-        let reply = GetStateResponse {
-            json_state: serde_json::to_string(&contract_state.tree)
-                .map_err(|e| Status::new(Code::Unknown, format!("{}", e)))?,
+        let manager = TreeDelegateManager::new(
+            Arc::new(Provider::new(MockProvider::new())),
+            &config,
+            None,
+        );
+        assert_eq!(manager.batch_concurrency, 3);
+        assert_eq!(manager.request_timeout, Some(Duration::from_secs(5)));
+        assert!(manager.folded_states.is_none());
+    }
+
+    #[test]
+    fn test_is_state_at() {
+        let state = TreeState {
+            caller_address: Default::default(),
+            identifier: Default::default(),
+            tree: None,
+            provenance: HashMap::new(),
+            block_number: U64::from(10),
+            block_hash: H256::from_low_u64_be(10),
+            config: Default::default(),
         };
 
-        Ok(Response::new(reply))
+        let at = |query_block: &QueryBlock| {
+            is_state_at(&state, query_block, Some(U64::from(12)), 2)
+        };
+        assert!(at(&QueryBlock::Latest));
+        assert!(at(&QueryBlock::BlockNumber(U64::from(10))));
+        assert!(at(&QueryBlock::BlockHash(H256::from_low_u64_be(10))));
+        assert!(!at(&QueryBlock::BlockNumber(U64::from(9))));
+        assert!(!at(&QueryBlock::BlockDepth(2)));
+
+        // a state that failed to fold up to the head is stale
+        let head = Some(U64::from(13));
+        assert!(!is_state_at(&state, &QueryBlock::Latest, head, 2));
+        assert!(is_state_at(&state, &QueryBlock::Latest, None, 0));
     }
 }
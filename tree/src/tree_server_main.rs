@@ -1,34 +1,220 @@
 #![warn(unused_extern_crates)]
-use state_fold::StateFoldEnvironment;
-use state_server_grpc::{serve_delegate_manager, wait_for_signal};
+use state_server_grpc::state_server::delegate_manager_server::DelegateManagerServer;
+use state_server_grpc::wait_for_signal;
+use tree::config::TreeServerConfig;
+use tree::failover::FailoverClient;
+use tree::folded_states::{follow_new_heads, poll_new_heads, FoldedStates};
+use tree::health::report_health;
+use tree::http_gateway::serve_http;
+use tree::logging::init_logging;
+use tree::metrics::serve_metrics;
+use tree::rpc_record::RecordingClient;
+use tree::tree_query_server::proto::tree_query_server::TreeQueryServer;
+use tree::tree_server::TreeDelegateManager;
 
-use ethers::providers::{Http, Provider};
-use ethers::types::U64;
-use std::convert::TryFrom;
+use ethers::providers::{Middleware, Provider, Ws};
+use futures::FutureExt;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::oneshot;
-
-static HTTP_URL: &'static str = "http://localhost:8545";
+use tonic::transport::Server;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let provider = Arc::new(Provider::<Http>::try_from(HTTP_URL).unwrap());
-    let env = StateFoldEnvironment::new(
+    let config = match TreeServerConfig::initialize() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    init_logging(config.log_format, &config.log_filter);
+
+    if config.is_websocket() {
+        let provider = Arc::new(Provider::new(
+            Ws::connect(config.rpc_url.as_str()).await?,
+        ));
+        let folded_states = Arc::new(
+            FoldedStates::with_max_trees(config.max_folded_trees)
+                .with_safety_margin(config.safety_margin),
+        );
+        let manager = Arc::new(TreeDelegateManager::new(
+            Arc::clone(&provider),
+            &config,
+            Some(Arc::clone(&folded_states)),
+        ));
+        let _ = track_trees(&config, &manager, &folded_states);
+
+        let following = tokio::spawn(follow_new_heads(
+            Arc::clone(&provider),
+            Arc::clone(&manager.env),
+            folded_states,
+        ));
+
+        // without new heads the folded states go stale, so stop and let the
+        // server be restarted
+        tokio::select! {
+            served = serve(&config, provider, manager) => served,
+            followed = following => {
+                let err: Box<dyn std::error::Error> = match followed {
+                    Ok(Ok(())) => "New heads subscription ended".into(),
+                    Ok(Err(e)) => e.into(),
+                    Err(e) => e.into(),
+                };
+                Err(err)
+            }
+        }
+    } else {
+        let client =
+            FailoverClient::new_http(&config.rpc_urls(), config.retry.clone())?;
+
+        match &config.record_rpc {
+            Some(path) => {
+                info!(path = %path.display(), "Recording node calls");
+                let client = RecordingClient::create(client, path)?;
+                run_http(&config, Provider::new(client)).await
+            }
+            None => run_http(&config, Provider::new(client)).await,
+        }
+    }
+}
+
+/// Serve from an HTTP node, which can't be subscribed to for new heads
+async fn run_http<M: Middleware + 'static>(
+    config: &TreeServerConfig,
+    provider: M,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let provider = Arc::new(provider);
+
+    // without subscriptions, only tracked trees are folded ahead of time
+    let folded_states = if config.track_trees.is_empty() {
+        None
+    } else {
+        Some(Arc::new(
+            FoldedStates::with_max_trees(config.max_folded_trees)
+                .with_safety_margin(config.safety_margin),
+        ))
+    };
+    let manager = Arc::new(TreeDelegateManager::new(
         Arc::clone(&provider),
-        0,
-        U64::from(0),
-        vec![],
-        4,
-    );
+        config,
+        folded_states.clone(),
+    ));
+
+    if let Some(folded_states) = folded_states {
+        let _ = track_trees(config, &manager, &folded_states);
+
+        let _ = tokio::spawn(poll_new_heads(
+            Arc::clone(&provider),
+            Arc::clone(&manager.env),
+            folded_states,
+            config.head_poll_interval,
+        ));
+    }
+
+    serve(config, provider, manager).await
+}
 
+/// Sync the trees tracked from startup in the background
+fn track_trees<M: Middleware + 'static>(
+    config: &TreeServerConfig,
+    manager: &TreeDelegateManager<M>,
+    folded_states: &Arc<FoldedStates>,
+) -> tokio::task::JoinHandle<()> {
+    folded_states.track(
+        Arc::clone(&manager.env),
+        Arc::clone(&manager.fold_config),
+        config.track_trees.clone(),
+        config.head_poll_interval,
+    )
+}
+
+async fn serve<M: Middleware + 'static>(
+    config: &TreeServerConfig,
+    provider: Arc<M>,
+    manager: Arc<TreeDelegateManager<M>>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
     let _ = tokio::spawn(wait_for_signal(shutdown_tx));
+    let shutdown = shutdown_rx.map(|_| ()).shared();
+
+    // not serving until the first readiness check passes
+    let (health_reporter, health_service) =
+        tonic_health::server::health_reporter();
+    let _ = tokio::spawn(report_health(
+        health_reporter,
+        provider,
+        config.health.clone(),
+        manager.folded_states.clone(),
+    ));
+
+    let grace_period = config.shutdown_grace_period;
+
+    let http = match config.http_listen_address {
+        Some(address) => Some(tokio::spawn(drain(
+            serve_http(Arc::clone(&manager), address, shutdown.clone()),
+            shutdown.clone(),
+            grace_period,
+        ))),
+        None => None,
+    };
+
+    let metrics = match config.metrics_listen_address {
+        Some(address) => Some(tokio::spawn(drain(
+            serve_metrics(address, shutdown.clone()),
+            shutdown.clone(),
+            grace_period,
+        ))),
+        None => None,
+    };
 
-    serve_delegate_manager(
-        "[::1]:50051",
-        tree::tree_server::TreeDelegateManager { env: Arc::new(env) },
-        shutdown_rx,
+    info!(address = %config.listen_address, "Serving tree queries");
+    let server = Server::builder()
+        .add_service(health_service)
+        // the same manager serves the JSON state, the typed queries and the
+        // HTTP gateway
+        .add_service(DelegateManagerServer::from_arc(Arc::clone(&manager)))
+        .add_service(TreeQueryServer::from_arc(manager))
+        .serve_with_shutdown(config.listen_address, shutdown.clone());
+
+    drain(
+        server,
+        shutdown.map(|_| info!("Shutting down, draining open requests")),
+        grace_period,
     )
-    .await
+    .await?;
+
+    for server in http.into_iter().chain(metrics) {
+        server.await??;
+    }
+
+    Ok(())
+}
+
+/// Run `server` until it stops after `shutdown` completes, dropping the
+/// requests still open `grace_period` after it
+async fn drain<E>(
+    server: impl Future<Output = Result<(), E>>,
+    shutdown: impl Future<Output = ()>,
+    grace_period: Duration,
+) -> Result<(), E> {
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result,
+        _ = async {
+            shutdown.await;
+            tokio::time::sleep(grace_period).await
+        } => {
+            warn!(
+                "Requests still open {:?} after shutdown were dropped",
+                grace_period
+            );
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,219 @@
+use crate::fold::tree_delegate::TreeState;
+use crate::tree_lib::Tree;
+use crate::tree_query_server::proto::{
+    tree_update::Update, DeepestChanged, TreeRewound, TreeSnapshot, TreeUpdate,
+    VerticesInserted,
+};
+use crate::tree_query_server::{to_proto_block, to_proto_vertex};
+
+/// Full tree as a single update
+pub fn snapshot(state: &TreeState) -> TreeUpdate {
+    let vertices = state
+        .tree
+        .as_ref()
+        .map(|tree| {
+            (0..tree.size() as u32)
+                .filter_map(|index| tree.get_vertex(index))
+                .map(to_proto_vertex)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    TreeUpdate {
+        update: Some(Update::Snapshot(TreeSnapshot {
+            block: Some(to_proto_block(state)),
+            vertices,
+            deepest: deepest_vertex(&state.tree),
+        })),
+    }
+}
+
+/// Updates taking a client from `previous` to `next`: a rewind if vertices of
+/// `previous` were orphaned, the vertices inserted since, and the new deepest
+/// vertex if it changed
+pub fn diff(previous: &TreeState, next: &TreeState) -> Vec<TreeUpdate> {
+    let mut updates = vec![];
+    if previous.block_hash == next.block_hash {
+        return updates;
+    }
+
+    let previous_size = size(&previous.tree);
+    let next_size = size(&next.tree);
+    let common = common_prefix(previous, next);
+
+    if common < previous_size {
+        updates.push(TreeUpdate {
+            update: Some(Update::Rewound(TreeRewound {
+                block: Some(to_proto_block(next)),
+                size: common as u64,
+            })),
+        });
+    }
+
+    if common < next_size {
+        let tree = next.tree.as_ref().unwrap();
+        updates.push(TreeUpdate {
+            update: Some(Update::VerticesInserted(VerticesInserted {
+                block: Some(to_proto_block(next)),
+                vertices: (common as u32..next_size as u32)
+                    .filter_map(|index| tree.get_vertex(index))
+                    .map(to_proto_vertex)
+                    .collect(),
+            })),
+        });
+    }
+
+    let deepest = deepest_vertex(&next.tree);
+    if deepest != deepest_vertex(&previous.tree) {
+        if let Some(deepest) = deepest {
+            updates.push(TreeUpdate {
+                update: Some(Update::DeepestChanged(DeepestChanged {
+                    block: Some(to_proto_block(next)),
+                    deepest: Some(deepest),
+                })),
+            });
+        }
+    }
+
+    updates
+}
+
+/// Number of leading vertices `previous` and `next` share. Vertices are only
+/// ever appended or rolled back, so the shared prefix ends at the latest
+/// vertex of `previous` inserted by the same log in `next`.
+fn common_prefix(previous: &TreeState, next: &TreeState) -> usize {
+    let mut common = size(&previous.tree).min(size(&next.tree));
+
+    while common > 0 {
+        let index = (common - 1) as u32;
+        let same_log = match (
+            previous.get_provenance(index),
+            next.get_provenance(index),
+        ) {
+            (Some(p), Some(n)) => {
+                p.block_hash == n.block_hash && p.log_index == n.log_index
+            }
+            _ => false,
+        };
+
+        if same_log {
+            break;
+        }
+        common -= 1;
+    }
+
+    common
+}
+
+fn size(tree: &Option<Tree>) -> usize {
+    tree.as_ref().map(|tree| tree.size()).unwrap_or(0)
+}
+
+fn deepest_vertex(
+    tree: &Option<Tree>,
+) -> Option<crate::tree_query_server::proto::Vertex> {
+    tree.as_ref()
+        .and_then(|tree| tree.get_deepest().and_then(|i| tree.get_vertex(i)))
+        .map(to_proto_vertex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fold::tree_delegate::VertexProvenance;
+
+    use ethers::types::{Address, H256, U256, U64};
+
+    /// State with one vertex per `(parent, block)`, at the last block
+    fn state(vertices: &[(u32, u64)]) -> TreeState {
+        let mut tree = Tree::default();
+        let mut provenance = im::HashMap::new();
+
+        for (index, (parent, block)) in vertices.iter().enumerate() {
+            tree = tree.insert_vertex(*parent).unwrap();
+            provenance.insert(
+                index as u32,
+                VertexProvenance {
+                    block_number: U64::from(*block),
+                    block_hash: H256::from_low_u64_be(*block),
+                    tx_hash: H256::zero(),
+                    log_index: U256::from(index),
+                    from: None,
+                },
+            );
+        }
+
+        let block = vertices.last().map(|(_, block)| *block).unwrap_or(0);
+        TreeState {
+            caller_address: Address::zero(),
+            identifier: U256::zero(),
+            tree: if vertices.is_empty() {
+                None
+            } else {
+                Some(tree)
+            },
+            provenance,
+            block_number: U64::from(block),
+            block_hash: H256::from_low_u64_be(block),
+            config: Default::default(),
+        }
+    }
+
+    fn inserted(update: &TreeUpdate) -> Vec<u32> {
+        match &update.update {
+            Some(Update::VerticesInserted(inserted)) => {
+                inserted.vertices.iter().map(|v| v.index).collect()
+            }
+            _ => panic!("Update should insert vertices"),
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let update = snapshot(&state(&[(0, 1), (0, 1), (1, 2)]));
+        match update.update {
+            Some(Update::Snapshot(snapshot)) => {
+                assert_eq!(snapshot.vertices.len(), 3);
+                assert_eq!(snapshot.deepest.unwrap().index, 2);
+                assert_eq!(snapshot.block.unwrap().number, 2);
+            }
+            _ => panic!("Update should be a snapshot"),
+        }
+    }
+
+    #[test]
+    fn test_diff_inserted() {
+        let previous = state(&[(0, 1), (0, 1)]);
+        let next = state(&[(0, 1), (0, 1), (0, 2), (2, 3)]);
+
+        let updates = diff(&previous, &next);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(inserted(&updates[0]), vec![2, 3]);
+        assert!(matches!(
+            &updates[1].update,
+            Some(Update::DeepestChanged(changed))
+                if changed.deepest.as_ref().unwrap().index == 3
+        ));
+
+        assert!(diff(&next, &next).is_empty(), "Same block has no updates");
+    }
+
+    #[test]
+    fn test_diff_rewound() {
+        let previous = state(&[(0, 1), (0, 1), (1, 2), (2, 2)]);
+        let next = state(&[(0, 1), (0, 1), (0, 5)]);
+
+        let updates = diff(&previous, &next);
+        assert_eq!(updates.len(), 3);
+        assert!(matches!(
+            &updates[0].update,
+            Some(Update::Rewound(rewound)) if rewound.size == 2
+        ));
+        assert_eq!(inserted(&updates[1]), vec![2]);
+        assert!(matches!(
+            &updates[2].update,
+            Some(Update::DeepestChanged(changed))
+                if changed.deepest.as_ref().unwrap().index == 1
+        ));
+    }
+}
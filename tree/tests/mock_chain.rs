@@ -0,0 +1,433 @@
+//! Syncing, folding and serving trees from an in-memory chain
+
+use tree::config::{TreeServerConfig, TreeServerFileConfig, TreeServerOpt};
+use tree::fold::tree_delegate::{TreeInitialState, TreeKey, TreeState};
+use tree::folded_states::FoldedStates;
+use tree::mock_chain::{vertex_inserted, MockChain};
+use tree::tree_query_server::proto::block_selector::Selector;
+use tree::tree_query_server::proto::tree_query_server::TreeQuery;
+use tree::tree_query_server::proto::tree_state_result::Result as StateResult;
+use tree::tree_query_server::proto::tree_update::Update;
+use tree::tree_query_server::proto::{
+    BlockSelector, GetDeepestRequest, GetStatesRequest, TreeLocator,
+};
+use tree::tree_server::{new_environment, TreeDelegateManager};
+use tree::tree_updates::diff;
+
+use state_fold::{types::QueryBlock, Foldable, StateFoldEnvironment};
+use state_server_grpc::state_server::delegate_manager_server::DelegateManager;
+use state_server_grpc::state_server::GetStateRequest;
+
+use ethers::types::{Address, H256, U256};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Request;
+
+fn config() -> TreeServerConfig {
+    TreeServerConfig::merge(
+        TreeServerOpt::default(),
+        TreeServerFileConfig::default(),
+    )
+    .unwrap()
+}
+
+fn tree_address() -> Address {
+    Address::from_low_u64_be(0x7ee)
+}
+
+fn vertex(parent: u32) -> ethers::types::Log {
+    vertex_inserted(tree_address(), U256::zero(), parent)
+}
+
+async fn state_at(
+    env: &StateFoldEnvironment<MockChain>,
+    query_block: QueryBlock,
+) -> TreeState {
+    TreeState::get_state_for_block(
+        &TreeInitialState::new(TreeKey::new(tree_address()), Arc::default()),
+        query_block,
+        env,
+    )
+    .await
+    .expect("Tree should be computed")
+    .state
+}
+
+fn size(state: &TreeState) -> usize {
+    state.tree.as_ref().map_or(0, |tree| tree.size())
+}
+
+#[tokio::test]
+async fn test_sync_and_fold() {
+    let chain = Arc::new(MockChain::new());
+    let env = new_environment(Arc::clone(&chain), &config());
+
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![
+        vertex(0),
+        vertex_inserted(Address::from_low_u64_be(1), U256::zero(), 0),
+    ]);
+    chain.mine(vec![vertex(1)]);
+
+    let state = state_at(&env, QueryBlock::Latest).await;
+    assert_eq!(size(&state), 3);
+    assert_eq!(state.tree.as_ref().unwrap().get_deepest(), Some(2));
+    assert_eq!(Some(state.block_hash), chain.head().hash);
+
+    // a block with a vertex, then one without any
+    chain.mine(vec![vertex(2)]);
+    let head = chain.mine_empty(1);
+
+    let state = state_at(&env, QueryBlock::BlockHash(head.hash.unwrap())).await;
+    assert_eq!(size(&state), 4);
+    assert_eq!(state.tree.as_ref().unwrap().get_deepest(), Some(3));
+    assert_eq!(Some(state.block_number), head.number);
+}
+
+#[tokio::test]
+async fn test_sync_from_genesis_block() {
+    let chain = Arc::new(MockChain::new());
+    let config = TreeServerConfig::merge(
+        TreeServerOpt {
+            genesis_block: Some(2),
+            ..Default::default()
+        },
+        TreeServerFileConfig::default(),
+    )
+    .unwrap();
+    let env = new_environment(Arc::clone(&chain), &config);
+
+    // logs before the genesis block are never queried
+    chain.mine(vec![vertex(0), vertex(0)]);
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(0)]);
+
+    let state = state_at(&env, QueryBlock::Latest).await;
+    assert_eq!(size(&state), 2);
+    assert_eq!(state.get_provenance(0).unwrap().block_number.as_u64(), 2);
+}
+
+#[tokio::test]
+async fn test_fetch_senders() {
+    let chain = Arc::new(MockChain::new());
+    let config = TreeServerConfig::merge(
+        TreeServerOpt {
+            fetch_senders: Some(true),
+            ..Default::default()
+        },
+        TreeServerFileConfig::default(),
+    )
+    .unwrap();
+    let manager = TreeDelegateManager::new(Arc::clone(&chain), &config, None);
+    let initial_state = TreeInitialState::new(
+        TreeKey::new(tree_address()),
+        Arc::clone(&manager.fold_config),
+    );
+    let alice = Address::from_low_u64_be(0xa11ce);
+    let bob = Address::from_low_u64_be(0xb0b);
+
+    chain.mine_from(alice, vec![vertex(0)]);
+    let synced = chain.mine_from(bob, vec![vertex(0), vertex(1)]);
+    let state = TreeState::get_state_for_block(
+        &initial_state,
+        QueryBlock::BlockHash(synced.hash.unwrap()),
+        &manager.env,
+    )
+    .await
+    .expect("Tree should be computed")
+    .state;
+
+    let sender = |state: &TreeState, index| {
+        state.get_provenance(index).unwrap().from.unwrap()
+    };
+    assert_eq!(sender(&state, 0), alice);
+    assert_eq!(sender(&state, 1), bob);
+    assert_eq!(sender(&state, 2), bob);
+
+    // folded blocks fetch the senders of their vertices too
+    let head = chain.mine_from(alice, vec![vertex(2)]);
+    let state = TreeState::get_state_for_block(
+        &initial_state,
+        QueryBlock::BlockHash(head.hash.unwrap()),
+        &manager.env,
+    )
+    .await
+    .expect("Tree should be computed")
+    .state;
+    assert_eq!(sender(&state, 3), alice);
+}
+
+#[tokio::test]
+async fn test_fold_across_reorg() {
+    let chain = Arc::new(MockChain::new());
+    let env = new_environment(Arc::clone(&chain), &config());
+
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(1)]);
+    assert_eq!(size(&state_at(&env, QueryBlock::Latest).await), 3);
+
+    // the last two blocks are replaced by three with a single vertex
+    let head = chain.reorg(2, vec![vec![], vec![vertex(0)], vec![]]);
+
+    let state = state_at(&env, QueryBlock::BlockHash(head.hash.unwrap())).await;
+    assert_eq!(size(&state), 2);
+    assert_eq!(Some(state.block_hash), head.hash);
+
+    let provenance = state.get_provenance(1).unwrap();
+    assert_eq!(provenance.block_number.as_u64(), 3);
+    assert_ne!(provenance.block_hash, H256::zero());
+}
+
+#[tokio::test]
+async fn test_folded_states_across_reorg() {
+    let chain = Arc::new(MockChain::new());
+    let manager = TreeDelegateManager::new(
+        Arc::clone(&chain),
+        &config(),
+        Some(Arc::new(FoldedStates::new())),
+    );
+    let folded_states = manager.folded_states.clone().unwrap();
+    let key = TreeKey::new(tree_address());
+
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(1)]);
+    folded_states
+        .track(
+            Arc::clone(&manager.env),
+            Arc::clone(&manager.fold_config),
+            vec![key],
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+    let before = folded_states.get(&key).await.unwrap();
+    assert_eq!(size(&before), 3);
+
+    let mut folds = folded_states.subscribe(key);
+    let head = chain.mine(vec![vertex(2)]);
+    folded_states.fold_all(&chain, &manager.env, &head).await;
+    assert_eq!(size(&folds.recv().await.unwrap()), 4);
+
+    // the last three blocks are replaced by two with a single vertex
+    let head = chain.reorg(3, vec![vec![], vec![vertex(0)]]);
+    folded_states.fold_all(&chain, &manager.env, &head).await;
+
+    let state = folded_states.get(&key).await.unwrap();
+    assert_eq!(size(&state), 2);
+    assert_eq!(Some(state.block_hash), head.hash);
+    assert_eq!(state.get_provenance(1).unwrap().block_number.as_u64(), 3);
+
+    // subscribers are told to drop the orphaned vertices
+    let folded = folds.recv().await.unwrap();
+    assert_eq!(folded.block_hash, state.block_hash);
+    let updates = diff(&before, &folded);
+    assert!(matches!(
+        &updates[0].update,
+        Some(Update::Rewound(rewound)) if rewound.size == 1
+    ));
+    assert!(matches!(
+        &updates[1].update,
+        Some(Update::VerticesInserted(inserted)) if inserted.vertices.len() == 1
+    ));
+}
+
+#[tokio::test]
+async fn test_grpc_service() {
+    let chain = Arc::new(MockChain::new());
+    let manager = TreeDelegateManager::new(
+        Arc::clone(&chain),
+        &config(),
+        Some(Arc::new(FoldedStates::new())),
+    );
+
+    chain.mine(vec![vertex(0)]);
+    let first = chain.mine(vec![vertex(0)]);
+    let head = chain.mine(vec![vertex(1)]);
+
+    let request = GetStateRequest {
+        json_initial_state: format!(
+            r#"{{"pos_instance": 0, "tree_address": "{:?}"}}"#,
+            tree_address()
+        ),
+    };
+    let response = manager
+        .get_state(Request::new(request))
+        .await
+        .expect("GetState should succeed")
+        .into_inner();
+
+    // version 1 responses are the tree alone
+    let json: Value = serde_json::from_str(&response.json_state).unwrap();
+    assert_eq!(json["vertices"].as_object().unwrap().len(), 3);
+    assert!(json.get("block_hash").is_none());
+
+    let request = GetStateRequest {
+        json_initial_state: format!(
+            r#"{{"version": 2, "tree_address": "{:?}"}}"#,
+            tree_address()
+        ),
+    };
+    let response = manager
+        .get_state(Request::new(request))
+        .await
+        .expect("GetState should succeed")
+        .into_inner();
+
+    let json: Value = serde_json::from_str(&response.json_state).unwrap();
+    assert_eq!(json["tree"]["vertices"].as_object().unwrap().len(), 3);
+    assert_eq!(json["provenance"].as_object().unwrap().len(), 3);
+    assert_eq!(json["block_hash"], serde_json::to_value(head.hash).unwrap());
+
+    // requests alone don't add trees to the folded ones
+    let folded_states = manager.folded_states.as_ref().unwrap();
+    assert!(folded_states.keys().await.is_empty());
+
+    // the typed service answers at an earlier block too
+    let locator = TreeLocator {
+        tree_address: format!("{:?}", tree_address()),
+        pos_instance: String::new(),
+    };
+    let response = manager
+        .get_deepest(Request::new(GetDeepestRequest {
+            tree: Some(locator),
+            block: Some(BlockSelector {
+                selector: Some(Selector::Number(
+                    first.number.unwrap().as_u64(),
+                )),
+            }),
+        }))
+        .await
+        .expect("GetDeepest should succeed")
+        .into_inner();
+
+    assert_eq!(
+        response.block.unwrap().number,
+        first.number.unwrap().as_u64()
+    );
+    assert_eq!(response.deepest.unwrap().depth, 1);
+
+    // unknown trees are empty
+    let response = manager
+        .get_state(Request::new(GetStateRequest {
+            json_initial_state: format!(
+                r#"{{"pos_instance": 0, "tree_address": "{:?}"}}"#,
+                Address::from_low_u64_be(2)
+            ),
+        }))
+        .await
+        .expect("GetState should succeed")
+        .into_inner();
+    let json: Value = serde_json::from_str(&response.json_state).unwrap();
+    assert!(json.is_null());
+}
+
+#[tokio::test]
+async fn test_batch_states() {
+    let chain = Arc::new(MockChain::new());
+    let manager = TreeDelegateManager::new(Arc::clone(&chain), &config(), None);
+    let other = Address::from_low_u64_be(0x7ef);
+
+    chain.mine(vec![vertex(0), vertex_inserted(other, U256::zero(), 0)]);
+    let first = chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(1)]);
+
+    let initial_state = |address: Address| {
+        format!(r#"{{"version": 2, "tree_address": "{:?}"}}"#, address)
+    };
+    let response = manager
+        .get_states(Request::new(GetStatesRequest {
+            json_initial_states: vec![
+                initial_state(tree_address()),
+                "{".to_string(),
+                initial_state(other),
+                format!(
+                    r#"{{"version": 2, "block": "latest", "tree_address":
+                        "{:?}"}}"#,
+                    tree_address()
+                ),
+            ],
+            block: Some(BlockSelector {
+                selector: Some(Selector::Number(
+                    first.number.unwrap().as_u64(),
+                )),
+            }),
+        }))
+        .await
+        .expect("GetStates should succeed")
+        .into_inner();
+
+    // every tree at the block of the request, in order
+    assert_eq!(
+        response.block.unwrap().number,
+        first.number.unwrap().as_u64()
+    );
+    let sizes: Vec<Option<usize>> = response
+        .states
+        .iter()
+        .map(|state| match state.result.as_ref().unwrap() {
+            StateResult::JsonState(json) => {
+                let json: Value = serde_json::from_str(json).unwrap();
+                Some(json["tree"]["vertices"].as_object().unwrap().len())
+            }
+            StateResult::Error(_) => None,
+        })
+        .collect();
+    assert_eq!(sizes, vec![Some(2), None, Some(1), None]);
+
+    // initial states can't select their own block
+    let invalid_argument = tonic::Code::InvalidArgument as i32;
+    assert!(matches!(
+        response.states[3].result.as_ref().unwrap(),
+        StateResult::Error(error) if error.code == invalid_argument
+    ));
+}
+
+#[tokio::test]
+async fn test_fold_behind_head() {
+    let chain = Arc::new(MockChain::new());
+    let config = TreeServerConfig::merge(
+        TreeServerOpt {
+            safety_margin: Some(2),
+            ..Default::default()
+        },
+        TreeServerFileConfig::default(),
+    )
+    .unwrap();
+    let folded_states = Arc::new(FoldedStates::new().with_safety_margin(2));
+    let manager = TreeDelegateManager::new(
+        Arc::clone(&chain),
+        &config,
+        Some(Arc::clone(&folded_states)),
+    );
+    let key = TreeKey::new(tree_address());
+
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(0)]);
+    chain.mine(vec![vertex(1)]);
+    chain.mine_empty(2);
+    folded_states
+        .insert(key, state_at(&manager.env, QueryBlock::Latest).await)
+        .await;
+
+    // the vertex of the new head is not folded until it is 2 blocks deep
+    let head = chain.mine(vec![vertex(2)]);
+    folded_states.fold_all(&chain, &manager.env, &head).await;
+    let state = folded_states.get(&key).await.unwrap();
+    assert_eq!(
+        state.block_number.as_u64(),
+        head.number.unwrap().as_u64() - 2
+    );
+    assert_eq!(size(&state), 3);
+
+    let head = chain.mine_empty(2);
+    folded_states.fold_all(&chain, &manager.env, &head).await;
+    let state = folded_states.get(&key).await.unwrap();
+    assert_eq!(
+        state.block_number.as_u64(),
+        head.number.unwrap().as_u64() - 2
+    );
+    assert_eq!(size(&state), 4);
+}